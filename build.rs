@@ -1,13 +1,36 @@
 extern crate skeptic;
 
 fn main() {
+    // Read back by the examples in tests/build-output-test.md
+    println!("cargo:rustc-cfg=skeptic_build_output");
+    println!("cargo:rustc-env=SKEPTIC_BUILD_OUTPUT=from build.rs");
+
     skeptic::generate_doc_tests(
         &[
             "README.md",
             "template-example.md",
+            "tests/build-output-test.md",
             "tests/hashtag-test.md",
             "tests/should-panic-test.md",
+            "tests/compile-fail-test.md",
+            "tests/edition-test.md",
+            "tests/features-test.md",
+            "tests/output-test.md",
+            "tests/renamed-dependency-test.md",
             "tests/section-names.md",
+            "tests/timeout-test.md",
         ],
     );
+
+    skeptic::Skeptic::new()
+        .doc("tests/merged-test.md")
+        .out_file_name("skeptic-merged-tests.rs")
+        .merge_examples(true)
+        .generate();
+
+    skeptic::Skeptic::new()
+        .doc("tests/cargo-backend-test.md")
+        .out_file_name("skeptic-cargo-tests.rs")
+        .backend(skeptic::Backend::Cargo)
+        .generate();
 }
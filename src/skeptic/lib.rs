@@ -4,15 +4,65 @@ extern crate pulldown_cmark as cmark;
 extern crate tempdir;
 extern crate glob;
 extern crate bytecount;
+#[cfg(unix)]
+extern crate libc;
 
 use std::env;
 use std::fs::File;
-use std::io::{self, Read, Write, Error as IoError};
+use std::io::{self, Read, Write};
 use std::mem;
 use std::path::{PathBuf, Path};
 use std::collections::HashMap;
+use std::time::Duration;
 use cmark::{Parser, Event, Tag};
 
+pub use errors::{Error, ErrorKind};
+use errors::ResultExt;
+
+mod errors {
+    use std::io;
+    use std::path::PathBuf;
+
+    error_chain! {
+        errors {
+            /// A markdown document passed for test generation does not exist.
+            MissingFile(path: PathBuf) {
+                description("markdown file not found")
+                display("markdown file not found: {}", path.display())
+            }
+            /// A path could not be represented as UTF-8, which is required
+            /// to pass it on to cargo and to the generated tests.
+            NonUtf8Path(path: PathBuf) {
+                description("path is not valid UTF-8")
+                display("path is not valid UTF-8: {}", path.display())
+            }
+            /// An environment variable that cargo sets for build scripts
+            /// is missing.
+            MissingEnvVar(name: String) {
+                description("missing build script environment variable")
+                display("environment variable `{}` is not set; \
+                         skeptic must be run from a build script", name)
+            }
+            /// A code block refers to a `skt-*` template that is not
+            /// defined in the document's `.skt.md` file.
+            MissingTemplate(name: String, file: PathBuf, line: usize) {
+                description("template not found")
+                display("{}:{}: template `{}` not found in {}.skt.md",
+                        file.display(), line, name, file.display())
+            }
+            /// A code block's info string could not be interpreted.
+            MalformedInfoString(info: String, file: PathBuf, line: usize, reason: String) {
+                description("malformed info string")
+                display("{}:{}: malformed info string `{}`: {}",
+                        file.display(), line, info, reason)
+            }
+        }
+        foreign_links {
+            Io(io::Error);
+        }
+    }
+}
+
 /// Returns a list of markdown files under a directory.
 ///
 /// # Usage
@@ -50,6 +100,9 @@ pub fn markdown_files_of_directory(dir: &str) -> Vec<PathBuf> {
 
 /// Generates tests for specified markdown files.
 ///
+/// This is a shorthand for configuring a [`Skeptic`](struct.Skeptic.html)
+/// builder with the given documents and default settings.
+///
 /// # Usage
 ///
 /// Generates doc tests for the specified files.
@@ -81,45 +134,382 @@ pub fn generate_doc_tests<T: Clone>(docs: &[T])
 where
     T: AsRef<Path>,
 {
-    // This shortcut is specifically so examples in skeptic's on
-    // readme can call this function in non-build.rs contexts, without
-    // panicking below.
-    if docs.is_empty() {
-        return;
+    Skeptic::new().docs(docs).generate();
+}
+
+/// Generates tests for specified markdown files, returning an error
+/// instead of panicking if the documents cannot be processed.
+///
+/// # Usage
+///
+/// ```rust,no_run
+/// extern crate skeptic;
+///
+/// fn main() {
+///     if let Err(e) = skeptic::try_generate_doc_tests(&["README.md"]) {
+///         println!("cargo:warning=skipping README tests: {}", e);
+///     }
+/// }
+/// ```
+pub fn try_generate_doc_tests<T>(docs: &[T]) -> Result<(), Error>
+where
+    T: Clone + AsRef<Path>,
+{
+    Skeptic::new().docs(docs).try_generate()
+}
+
+/// How examples are compiled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Invoke `rustc` directly, passing it the libraries cargo built for
+    /// the tests. This is the default.
+    #[default]
+    Rustc,
+    /// Build each example as a small cargo package in the target
+    /// directory, which depends on the package under test and on its
+    /// dependencies, using the versions locked in `Cargo.lock`. This is
+    /// slower, but features, renamed dependencies and procedural macros
+    /// work as they do for the package itself. The `--cfg` flags and
+    /// environment variables set by the package's build script, which
+    /// cargo does not pass on to dependents, are set by the example's.
+    Cargo,
+}
+
+/// How the generated test functions are named.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TestNaming {
+    /// `<file>_sect_<section>_line_<n>`, falling back to `<file>_line_<n>`
+    /// for code blocks that precede any section heading. This is the default.
+    #[default]
+    SectionAndLine,
+    /// `<file>_line_<n>`, regardless of the enclosing section.
+    Line,
+}
+
+/// A builder for configuring test generation.
+///
+/// Settings that are not specified explicitly are taken from the
+/// environment cargo provides to build scripts (`OUT_DIR`,
+/// `CARGO_MANIFEST_DIR` and `TARGET`).
+///
+/// # Usage
+///
+/// ```rust,no_run
+/// extern crate skeptic;
+///
+/// use skeptic::{Skeptic, TestNaming};
+///
+/// fn main() {
+///     Skeptic::new()
+///         .doc("README.md")
+///         .docs(&skeptic::markdown_files_of_directory("book/"))
+///         .heading_depth(3)
+///         .naming(TestNaming::Line)
+///         .generate();
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Skeptic {
+    docs: Vec<PathBuf>,
+    root_dir: Option<PathBuf>,
+    out_dir: Option<PathBuf>,
+    out_file_name: String,
+    target_triple: Option<String>,
+    default_template: Option<String>,
+    heading_depth: u32,
+    naming: TestNaming,
+    merge_examples: bool,
+    backend: Backend,
+    output_limit: usize,
+    timeout: Option<Duration>,
+}
+
+impl Default for Skeptic {
+    fn default() -> Skeptic {
+        Skeptic::new()
+    }
+}
+
+impl Skeptic {
+    /// Creates a builder with no documents and default settings.
+    pub fn new() -> Skeptic {
+        Skeptic {
+            docs: Vec::new(),
+            root_dir: None,
+            out_dir: None,
+            out_file_name: String::from("skeptic-tests.rs"),
+            target_triple: None,
+            default_template: None,
+            heading_depth: 2,
+            naming: TestNaming::default(),
+            merge_examples: false,
+            backend: Backend::default(),
+            output_limit: rt::DEFAULT_OUTPUT_LIMIT,
+            timeout: None,
+        }
+    }
+
+    /// Adds a markdown document to generate tests for.
+    ///
+    /// Relative paths are resolved against the root directory. Template
+    /// files (`*.skt.md`) are skipped.
+    pub fn doc<P: AsRef<Path>>(&mut self, doc: P) -> &mut Skeptic {
+        self.docs.push(doc.as_ref().to_owned());
+        self
+    }
+
+    /// Adds several markdown documents to generate tests for.
+    pub fn docs<P: AsRef<Path>>(&mut self, docs: &[P]) -> &mut Skeptic {
+        self.docs.extend(docs.iter().map(|doc| doc.as_ref().to_owned()));
+        self
+    }
+
+    /// Sets the directory the documents are relative to and whose
+    /// `Cargo.toml` describes the dependencies available to examples.
+    ///
+    /// Defaults to `CARGO_MANIFEST_DIR`.
+    pub fn root_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Skeptic {
+        self.root_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Sets the build script output directory.
+    ///
+    /// Defaults to `OUT_DIR`.
+    pub fn out_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Skeptic {
+        self.out_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Sets the name of the generated file within the output directory.
+    ///
+    /// Defaults to `skeptic-tests.rs`.
+    pub fn out_file_name(&mut self, name: &str) -> &mut Skeptic {
+        self.out_file_name = name.to_owned();
+        self
+    }
+
+    /// Sets the target triple examples are compiled for.
+    ///
+    /// Defaults to `TARGET`.
+    pub fn target(&mut self, triple: &str) -> &mut Skeptic {
+        self.target_triple = Some(triple.to_owned());
+        self
+    }
+
+    /// Sets the template used for examples that have neither a `skt-*`
+    /// template nor a document-global `skeptic-template`.
+    ///
+    /// Like any other template this is a format string taking a single
+    /// `{}` argument.
+    pub fn default_template(&mut self, template: &str) -> &mut Skeptic {
+        self.default_template = Some(template.to_owned());
+        self
+    }
+
+    /// Sets the deepest heading level that starts a new section for
+    /// the purpose of test naming.
+    ///
+    /// Defaults to `2`, i.e. `#` and `##` headings.
+    pub fn heading_depth(&mut self, depth: u32) -> &mut Skeptic {
+        self.heading_depth = depth;
+        self
+    }
+
+    /// Sets how the generated test functions are named.
+    pub fn naming(&mut self, naming: TestNaming) -> &mut Skeptic {
+        self.naming = naming;
+        self
+    }
+
+    /// Compiles examples that allow it into a single binary per edition,
+    /// instead of one binary per example, which is much faster for
+    /// documents with many examples.
+    ///
+    /// Each merged example becomes a module of the binary, which runs
+    /// the example it is asked for. Examples are compiled on their own
+    /// as usual if they are `ignore`, `no_run` or `compile_fail`, contain
+    /// crate-level items such as inner attributes or `extern crate`, or
+    /// use the 2015 edition. If a merged binary fails to compile, its
+    /// examples fall back to being compiled on their own too.
+    ///
+    /// Off by default.
+    pub fn merge_examples(&mut self, merge: bool) -> &mut Skeptic {
+        self.merge_examples = merge;
+        self
+    }
+
+    /// Sets how examples are compiled.
+    pub fn backend(&mut self, backend: Backend) -> &mut Skeptic {
+        self.backend = backend;
+        self
     }
 
-    let docs = docs.iter()
-        .cloned()
-        .map(|path| path.as_ref().to_str().unwrap().to_owned())
-        .filter(|d| !d.ends_with(".skt.md"))
-        .collect::<Vec<_>>();
+    /// Sets how many bytes of the stdout and of the stderr of an example
+    /// are kept to be checked and shown in failure messages. The rest is
+    /// only passed on as the example prints it.
+    ///
+    /// Defaults to 16 KiB.
+    pub fn output_limit(&mut self, bytes: usize) -> &mut Skeptic {
+        self.output_limit = bytes;
+        self
+    }
 
-    // Inform cargo that it needs to rerun the build script if one of the skeptic files are
-    // modified
-    for doc in &docs {
-        println!("cargo:rerun-if-changed={}", doc);
+    /// Sets how long examples may run before they are killed, along with
+    /// any process they started, and their test fails. Examples can set
+    /// their own limit with a `timeout` info string, like `timeout=10s`.
+    ///
+    /// By default, examples may run for as long as they like.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Skeptic {
+        self.timeout = Some(timeout);
+        self
+    }
 
-        let skt = format!("{}.skt.md", doc);
-        if Path::new(&skt).exists() {
-            println!("cargo:rerun-if-changed={}", skt);
+    /// Generates the tests and writes them to the output file.
+    ///
+    /// # Panics
+    ///
+    /// Panics with a description of the problem if test generation fails.
+    /// Use [`try_generate`](#method.try_generate) to handle errors instead.
+    pub fn generate(&self) {
+        if let Err(e) = self.try_generate() {
+            panic!("failed to generate skeptic tests: {}", e);
         }
     }
 
-    let out_dir = env::var("OUT_DIR").unwrap();
-    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    /// Generates the tests and writes them to the output file, returning
+    /// an error if a document or template cannot be processed.
+    pub fn try_generate(&self) -> Result<(), Error> {
+        // This shortcut is specifically so examples in skeptic's on
+        // readme can call this function in non-build.rs contexts, without
+        // failing below.
+        if self.docs.is_empty() {
+            return Ok(());
+        }
 
-    let mut out_file = PathBuf::from(out_dir.clone());
-    out_file.push("skeptic-tests.rs");
+        let mut docs = Vec::new();
+        for path in &self.docs {
+            let doc = path_to_str(path)?;
+            if !doc.ends_with(".skt.md") {
+                docs.push(doc.to_owned());
+            }
+        }
 
-    let config = Config {
-        out_dir: PathBuf::from(out_dir),
-        root_dir: PathBuf::from(cargo_manifest_dir),
-        out_file: out_file,
-        target_triple: env::var("TARGET").expect("could not get target triple"),
-        docs: docs,
-    };
+        let out_dir = match self.out_dir {
+            Some(ref dir) => dir.clone(),
+            None => PathBuf::from(env_var("OUT_DIR")?),
+        };
+        let root_dir = match self.root_dir {
+            Some(ref dir) => dir.clone(),
+            None => PathBuf::from(env_var("CARGO_MANIFEST_DIR")?),
+        };
+        let target_triple = match self.target_triple {
+            Some(ref triple) => triple.clone(),
+            None => env_var("TARGET")?,
+        };
+
+        // Inform cargo that it needs to rerun the build script if one of the skeptic files are
+        // modified. The documents are relative to the root directory, which
+        // need not be the one cargo runs the build script in.
+        for doc in &docs {
+            let path = root_dir.join(doc);
+            println!("cargo:rerun-if-changed={}", path.display());
+
+            let skt = root_dir.join(format!("{}.skt.md", doc));
+            if skt.exists() {
+                println!("cargo:rerun-if-changed={}", skt.display());
+            }
+        }
+        // The tests are generated with the wrappers and flags cargo runs
+        // rustc with
+        for var in &["RUSTC_WRAPPER", "RUSTC_WORKSPACE_WRAPPER", "RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS"] {
+            println!("cargo:rerun-if-env-changed={}", var);
+        }
+
+        // Cargo tells build scripts about the profile the package is built
+        // with. Otherwise, assume the default `dev` profile.
+        let (opt_level, debug_assertions) = match env::var("OPT_LEVEL") {
+            Ok(opt_level) => (opt_level, env::var_os("CARGO_CFG_DEBUG_ASSERTIONS").is_some()),
+            Err(_) => (String::from("0"), true),
+        };
+
+        let config = Config {
+            out_file: out_dir.join(&self.out_file_name),
+            out_dir,
+            root_dir,
+            target_triple,
+            docs,
+            default_template: self.default_template.clone(),
+            heading_depth: self.heading_depth,
+            naming: self.naming,
+            merge_examples: self.merge_examples,
+            backend: self.backend,
+            features: enabled_features(),
+            opt_level,
+            debug_assertions,
+            rustc_wrapper: env_wrapper("RUSTC_WRAPPER"),
+            rustc_workspace_wrapper: env_wrapper("RUSTC_WORKSPACE_WRAPPER"),
+            rustflags: rustflags(),
+            output_limit: self.output_limit,
+            timeout: self.timeout,
+        };
+
+        run(&config)
+    }
+}
+
+/// The features enabled for the package, which cargo tells build scripts
+/// with a `CARGO_FEATURE_<name>` variable per feature, named in
+/// lowercase with `_` instead of `-`.
+fn enabled_features() -> Vec<String> {
+    let mut features: Vec<String> = env::vars_os()
+        .filter_map(|(key, _)| key.into_string().ok())
+        .filter(|key| key.starts_with("CARGO_FEATURE_"))
+        .map(|key| key["CARGO_FEATURE_".len()..].to_lowercase())
+        .collect();
+    features.sort();
+    features
+}
+
+/// A program cargo runs rustc through, which an empty variable disables.
+fn env_wrapper(var: &str) -> Option<String> {
+    env::var(var).ok().filter(|wrapper| !wrapper.is_empty())
+}
+
+/// The flags cargo passes to rustc. Cargo gives them to build scripts
+/// in `CARGO_ENCODED_RUSTFLAGS`, separated by `\x1f`, which older
+/// versions of cargo don't set.
+fn rustflags() -> Vec<String> {
+    match env::var("CARGO_ENCODED_RUSTFLAGS") {
+        Ok(ref flags) if flags.is_empty() => Vec::new(),
+        Ok(flags) => flags.split('\x1f').map(String::from).collect(),
+        Err(_) => env::var("RUSTFLAGS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(String::from)
+            .collect(),
+    }
+}
 
-    run(&config);
+/// The features an example needs that are not enabled.
+fn missing_features<'a>(config: &Config, test: &'a Test) -> Vec<&'a str> {
+    test.features
+        .iter()
+        .filter(|feature| {
+            let name = feature.to_lowercase().replace("-", "_");
+            !config.features.contains(&name)
+        })
+        .map(|feature| &feature[..])
+        .collect()
+}
+
+fn env_var(name: &str) -> Result<String, Error> {
+    env::var(name).map_err(|_| ErrorKind::MissingEnvVar(name.to_owned()).into())
+}
+
+fn path_to_str(path: &Path) -> Result<&str, Error> {
+    path.to_str()
+        .ok_or_else(|| ErrorKind::NonUtf8Path(path.to_owned()).into())
 }
 
 struct Config {
@@ -128,20 +518,46 @@ struct Config {
     out_file: PathBuf,
     target_triple: String,
     docs: Vec<String>,
+    default_template: Option<String>,
+    heading_depth: u32,
+    naming: TestNaming,
+    merge_examples: bool,
+    backend: Backend,
+    // The enabled features, named as in `CARGO_FEATURE_*`
+    features: Vec<String>,
+    opt_level: String,
+    debug_assertions: bool,
+    rustc_wrapper: Option<String>,
+    rustc_workspace_wrapper: Option<String>,
+    rustflags: Vec<String>,
+    output_limit: usize,
+    timeout: Option<Duration>,
 }
 
-fn run(config: &Config) {
-    let tests = extract_tests(config).unwrap();
-    emit_tests(config, tests).unwrap();
+fn run(config: &Config) -> Result<(), Error> {
+    let tests = extract_tests(config)?;
+    emit_tests(config, tests)
 }
 
 struct Test {
     name: String,
+    line: usize,
     text: Vec<String>,
     ignore: bool,
     no_run: bool,
     should_panic: bool,
+    // Text the example's stderr must contain when it panics
+    panic_message: Option<String>,
+    compile_fail: bool,
+    error_codes: Vec<String>,
+    edition: Option<String>,
+    features: Vec<String>,
+    // How long the example may run, if it sets a limit
+    timeout: Option<Duration>,
     template: Option<String>,
+    // The expected output and the index of its `skt-output` block among
+    // those of the document
+    expected_output: Option<(usize, String)>,
 }
 
 struct DocTestSuite {
@@ -149,18 +565,19 @@ struct DocTestSuite {
 }
 
 struct DocTest {
+    doc: String,
     path: PathBuf,
     old_template: Option<String>,
     tests: Vec<Test>,
     templates: HashMap<String, String>,
 }
 
-fn extract_tests(config: &Config) -> Result<DocTestSuite, IoError> {
+fn extract_tests(config: &Config) -> Result<DocTestSuite, Error> {
     let mut doc_tests = Vec::new();
     for doc in &config.docs {
         let path = &mut config.root_dir.clone();
         path.push(doc);
-        let new_tests = extract_tests_from_file(path)?;
+        let new_tests = extract_tests_from_file(config, doc, path)?;
         doc_tests.push(new_tests);
     }
     Ok(DocTestSuite { doc_tests: doc_tests })
@@ -169,21 +586,25 @@ fn extract_tests(config: &Config) -> Result<DocTestSuite, IoError> {
 enum Buffer {
     None,
     Code(Vec<String>),
+    Output(String),
     Header(String),
 }
 
-fn extract_tests_from_file(path: &Path) -> Result<DocTest, IoError> {
-    let mut file = File::open(path)?;
+fn extract_tests_from_file(config: &Config, doc: &str, path: &Path) -> Result<DocTest, Error> {
+    if !path.exists() {
+        bail!(ErrorKind::MissingFile(path.to_owned()));
+    }
     let s = &mut String::new();
-    file.read_to_string(s)?;
-
-    let file_stem = &sanitize_test_name(path.file_stem().unwrap().to_str().unwrap());
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(s))
+        .chain_err(|| format!("failed to read {}", path.display()))?;
 
-    let tests = extract_tests_from_string(s, file_stem);
+    let tests = extract_tests_from_string(config, s, path)?;
 
     let templates = load_templates(path)?;
 
     Ok(DocTest {
+        doc: doc.to_owned(),
         path: path.to_owned(),
         old_template: tests.1,
         tests: tests.0,
@@ -191,14 +612,24 @@ fn extract_tests_from_file(path: &Path) -> Result<DocTest, IoError> {
     })
 }
 
-fn extract_tests_from_string(s: &str, file_stem: &str) -> (Vec<Test>, Option<String>) {
-    let mut tests = Vec::new();
+fn extract_tests_from_string(
+    config: &Config,
+    s: &str,
+    path: &Path,
+) -> Result<(Vec<Test>, Option<String>), Error> {
+    let file_stem = path.file_stem()
+        .ok_or_else(|| ErrorKind::MissingFile(path.to_owned()))?;
+    let file_stem = &sanitize_test_name(path_to_str(Path::new(file_stem))?);
+    let mut tests: Vec<Test> = Vec::new();
     let mut buffer = Buffer::None;
     let mut parser = Parser::new(s);
     let mut section = None;
     let mut code_block_start = 0;
     // Oh this isn't actually a test but a legacy template
     let mut old_template = None;
+    // The test that a `skt-output` block would belong to
+    let mut preceding_test: Option<usize> = None;
+    let mut output_blocks = 0;
 
     // In order to call get_offset() on the parser,
     // this loop must not hold an exclusive reference to the parser.
@@ -211,19 +642,41 @@ fn extract_tests_from_string(s: &str, file_stem: &str) -> (Vec<Test>, Option<Str
             break;
         };
         match event {
-            Event::Start(Tag::Header(level)) if level < 3 => {
+            Event::Start(Tag::Header(level)) if level as u32 <= config.heading_depth => {
                 buffer = Buffer::Header(String::new());
             }
-            Event::End(Tag::Header(level)) if level < 3 => {
+            Event::End(Tag::Header(level)) if level as u32 <= config.heading_depth => {
                 let cur_buffer = mem::replace(&mut buffer, Buffer::None);
                 if let Buffer::Header(sect) = cur_buffer {
                     section = Some(sanitize_test_name(&sect));
                 }
             }
             Event::Start(Tag::CodeBlock(ref info)) => {
-                let code_block_info = parse_code_block_info(info);
-                if code_block_info.is_rust {
-                    buffer = Buffer::Code(Vec::new());
+                // The parser is now past the fence line, so this counts it in.
+                let line = bytecount::count(&s.as_bytes()[0..parser.get_offset()], b'\n');
+                let malformed = |reason: String| {
+                    ErrorKind::MalformedInfoString(info.to_string(), path.to_owned(), line, reason)
+                };
+                let code_block_info = parse_code_block_info(info).map_err(&malformed)?;
+                if code_block_info.is_output {
+                    let test = match preceding_test.take() {
+                        Some(index) => &tests[index],
+                        None => bail!(malformed(String::from(
+                            "`skt-output` must directly follow a Rust example",
+                        ))),
+                    };
+                    if test.no_run || test.compile_fail || test.should_panic {
+                        bail!(malformed(String::from(
+                            "`skt-output` cannot follow a `no_run`, `compile_fail` \
+                             or `should_panic` example",
+                        )));
+                    }
+                    buffer = Buffer::Output(String::new());
+                } else {
+                    preceding_test = None;
+                    if code_block_info.is_rust {
+                        buffer = Buffer::Code(Vec::new());
+                    }
                 }
             }
             Event::Text(text) => {
@@ -234,37 +687,57 @@ fn extract_tests_from_string(s: &str, file_stem: &str) -> (Vec<Test>, Option<Str
                     buf.push(text.into_owned());
                 } else if let Buffer::Header(ref mut buf) = buffer {
                     buf.push_str(&*text);
+                } else if let Buffer::Output(ref mut buf) = buffer {
+                    buf.push_str(&*text);
                 }
             }
             Event::End(Tag::CodeBlock(ref info)) => {
-                let code_block_info = parse_code_block_info(info);
-                if let Buffer::Code(buf) = mem::replace(&mut buffer, Buffer::None) {
+                // Already validated when the code block started.
+                let code_block_info = parse_code_block_info(info).unwrap();
+                let cur_buffer = mem::replace(&mut buffer, Buffer::None);
+                if let Buffer::Output(output) = cur_buffer {
+                    tests.last_mut().unwrap().expected_output = Some((output_blocks, output));
+                    output_blocks += 1;
+                } else if let Buffer::Code(buf) = cur_buffer {
                     if code_block_info.is_old_template {
                         old_template = Some(buf.into_iter().collect())
                     } else {
-                        let name = if let Some(ref section) = section {
-                            format!("{}_sect_{}_line_{}", file_stem, section, code_block_start)
-                        } else {
-                            format!("{}_line_{}", file_stem, code_block_start)
+                        let name = match (config.naming, section.as_ref()) {
+                            (TestNaming::SectionAndLine, Some(section)) => format!(
+                                "{}_sect_{}_line_{}",
+                                file_stem,
+                                section,
+                                code_block_start
+                            ),
+                            _ => format!("{}_line_{}", file_stem, code_block_start),
                         };
                         tests.push(Test {
                             name: name,
+                            line: code_block_start,
                             text: buf,
                             ignore: code_block_info.ignore,
                             no_run: code_block_info.no_run,
                             should_panic: code_block_info.should_panic,
+                            panic_message: code_block_info.panic_message,
+                            compile_fail: code_block_info.compile_fail,
+                            error_codes: code_block_info.error_codes,
+                            edition: code_block_info.edition,
+                            features: code_block_info.features,
+                            timeout: code_block_info.timeout,
                             template: code_block_info.template,
+                            expected_output: None,
                         });
+                        preceding_test = Some(tests.len() - 1);
                     }
                 }
             }
             _ => (),
         }
     }
-    (tests, old_template)
+    Ok((tests, old_template))
 }
 
-fn load_templates(path: &Path) -> Result<HashMap<String, String>, IoError> {
+fn load_templates(path: &Path) -> Result<HashMap<String, String>, Error> {
     let file_name = format!(
         "{}.skt.md",
         path.file_name().expect("no file name").to_string_lossy()
@@ -276,17 +749,27 @@ fn load_templates(path: &Path) -> Result<HashMap<String, String>, IoError> {
 
     let mut map = HashMap::new();
 
-    let mut file = File::open(path)?;
     let s = &mut String::new();
-    file.read_to_string(s)?;
-    let parser = Parser::new(s);
+    File::open(&path)
+        .and_then(|mut file| file.read_to_string(s))
+        .chain_err(|| format!("failed to read {}", path.display()))?;
+    let mut parser = Parser::new(s);
 
     let mut code_buffer = None;
 
-    for event in parser {
+    while let Some(event) = parser.next() {
         match event {
             Event::Start(Tag::CodeBlock(ref info)) => {
-                let code_block_info = parse_code_block_info(info);
+                let code_block_info = parse_code_block_info(info).map_err(|reason| {
+                    // The parser is now past the fence line, so this counts it in.
+                    let line = bytecount::count(&s.as_bytes()[0..parser.get_offset()], b'\n');
+                    ErrorKind::MalformedInfoString(
+                        info.to_string(),
+                        path.clone(),
+                        line,
+                        reason,
+                    )
+                })?;
                 if code_block_info.is_rust {
                     code_buffer = Some(Vec::new());
                 }
@@ -297,7 +780,8 @@ fn load_templates(path: &Path) -> Result<HashMap<String, String>, IoError> {
                 }
             }
             Event::End(Tag::CodeBlock(ref info)) => {
-                let code_block_info = parse_code_block_info(info);
+                // Already validated when the code block started.
+                let code_block_info = parse_code_block_info(info).unwrap();
                 if let Some(buf) = code_buffer.take() {
                     if let Some(t) = code_block_info.template {
                         map.insert(t, buf.into_iter().collect());
@@ -326,22 +810,37 @@ fn sanitize_test_name(s: &str) -> String {
         .join("_")
 }
 
-fn parse_code_block_info(info: &str) -> CodeBlockInfo {
-    // Same as rustdoc
-    let tokens = info.split(|c: char| !(c == '_' || c == '-' || c.is_alphanumeric()));
-
+/// Interprets a code block's info string, returning a description of
+/// the problem if it is malformed.
+fn parse_code_block_info(info: &str) -> Result<CodeBlockInfo, String> {
     let mut seen_rust_tags = false;
     let mut seen_other_tags = false;
+    // Whether the previous token listed features, which the next may
+    // continue, as in `features=serde,async`
+    let mut in_features = false;
+    // Only the info strings of Rust examples must be well-formed, so
+    // mistakes are reported once it is known whether the block is one
+    let mut error = None;
+    let (tokens, split_error) = split_info_string(info);
     let mut info = CodeBlockInfo {
         is_rust: false,
         should_panic: false,
+        panic_message: None,
         ignore: false,
         no_run: false,
+        compile_fail: false,
+        error_codes: Vec::new(),
+        edition: None,
+        features: Vec::new(),
+        timeout: None,
+        is_output: false,
         is_old_template: false,
         template: None,
     };
 
     for token in tokens {
+        let continues_features = in_features;
+        in_features = false;
         match token {
             "" => {}
             "rust" => {
@@ -352,6 +851,15 @@ fn parse_code_block_info(info: &str) -> CodeBlockInfo {
                 info.should_panic = true;
                 seen_rust_tags = true
             }
+            _ if token.starts_with("should_panic=") => {
+                let message = unquote(&token["should_panic=".len()..]);
+                if message.is_empty() {
+                    error = error.or(Some(String::from("the expected panic message is empty")));
+                }
+                info.should_panic = true;
+                info.panic_message = Some(message.to_string());
+                seen_rust_tags = true
+            }
             "ignore" => {
                 info.ignore = true;
                 seen_rust_tags = true
@@ -360,56 +868,228 @@ fn parse_code_block_info(info: &str) -> CodeBlockInfo {
                 info.no_run = true;
                 seen_rust_tags = true;
             }
+            "compile_fail" => {
+                info.compile_fail = true;
+                seen_rust_tags = true;
+            }
+            _ if is_error_code(token) => {
+                info.error_codes.push(token.to_owned());
+                seen_rust_tags = true;
+            }
+            "edition2015" | "edition2018" | "edition2021" | "edition2024" => {
+                info.edition = Some(token[7..].to_string());
+                seen_rust_tags = true;
+            }
+            _ if token.starts_with("edition") && token[7..].chars().all(|c| c.is_ascii_digit()) => {
+                error = error.or(Some(format!("unknown edition `{}`", &token[7..])));
+            }
             "skeptic-template" => {
                 info.is_old_template = true;
                 seen_rust_tags = true
             }
+            "skt-output" => info.is_output = true,
             _ if token.starts_with("skt-") => {
+                if token.len() == 4 {
+                    error = error.or(Some(String::from("`skt-` must be followed by a template name")));
+                }
                 info.template = Some(token[4..].to_string());
                 seen_rust_tags = true;
             }
+            _ if token.starts_with("features=") => {
+                let features = unquote(&token["features=".len()..]);
+                info.features.extend(
+                    features.split(',').map(str::trim).filter(|f| !f.is_empty()).map(String::from),
+                );
+                if info.features.is_empty() {
+                    error = error.or(Some(String::from("`features=` must be followed by feature names")));
+                }
+                in_features = !token.ends_with('"');
+                seen_rust_tags = true;
+            }
+            _ if token.starts_with("timeout=") => {
+                match parse_timeout(unquote(&token["timeout=".len()..])) {
+                    Ok(timeout) => info.timeout = Some(timeout),
+                    Err(e) => error = error.or(Some(e)),
+                }
+                seen_rust_tags = true;
+            }
+            _ if continues_features && !token.contains('=') => {
+                info.features.push(token.to_owned());
+                in_features = true;
+            }
             _ => seen_other_tags = true,
         }
     }
 
     info.is_rust &= !seen_other_tags || seen_rust_tags;
 
-    info
+    if !info.is_rust {
+        return Ok(info);
+    }
+    if let Some(error) = split_error.or(error) {
+        return Err(error);
+    }
+    if !info.error_codes.is_empty() && !info.compile_fail {
+        return Err(String::from("error codes are only allowed with `compile_fail`"));
+    }
+    if info.compile_fail && info.should_panic {
+        return Err(String::from("`compile_fail` examples are never run, so cannot `should_panic`"));
+    }
+    if info.no_run && info.panic_message.is_some() {
+        return Err(String::from("`no_run` examples are never run, so cannot check a panic message"));
+    }
+    if (info.no_run || info.compile_fail) && info.timeout.is_some() {
+        return Err(String::from("examples that are never run cannot have a `timeout`"));
+    }
+
+    Ok(info)
+}
+
+/// Splits an info string into its words.
+///
+/// Like rustdoc, words are separated by anything but letters, digits,
+/// `_` and `-`. A word may also carry a value after `=`, which can be
+/// quoted to include any character but `"`, as in
+/// `should_panic="index out of bounds"`.
+///
+/// An unterminated quote runs to the end of the info string, and is
+/// returned as an error alongside the words.
+fn split_info_string(info: &str) -> (Vec<&str>, Option<String>) {
+    let is_word_char = |c: char| c == '_' || c == '-' || c.is_alphanumeric();
+    let mut tokens = Vec::new();
+    let mut error = None;
+    let mut rest = info;
+    while !rest.is_empty() {
+        let word_len = rest.find(|c: char| !is_word_char(c)).unwrap_or(rest.len());
+        let mut len = word_len;
+        if word_len > 0 && rest[word_len..].starts_with('=') {
+            let value = &rest[word_len + 1..];
+            len += 1 + match value.strip_prefix('"') {
+                Some(quoted) => match quoted.find('"') {
+                    Some(end) => end + 2,
+                    None => {
+                        error = Some(format!("unterminated quote in `{}`", rest));
+                        value.len()
+                    }
+                },
+                None => value.find(|c: char| !is_word_char(c)).unwrap_or(value.len()),
+            };
+        }
+        if len > 0 {
+            tokens.push(&rest[..len]);
+        }
+        // Skip the token, or the separator that follows it
+        let skip = if len > 0 { len } else { rest.chars().next().unwrap().len_utf8() };
+        rest = &rest[skip..];
+    }
+    (tokens, error)
+}
+
+/// Removes the quotes around an info string value, if any.
+fn unquote(value: &str) -> &str {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+/// Parses the value of a `timeout` info string: a whole number of
+/// milliseconds, seconds or minutes, as in `500ms`, `10s` or `2m`.
+/// A number without a unit is in seconds.
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let number = value[..digits].parse::<u64>();
+    let timeout = match (number, &value[digits..]) {
+        (Ok(number), "ms") => Duration::from_millis(number),
+        (Ok(number), "s") | (Ok(number), "") => Duration::from_secs(number),
+        (Ok(number), "m") => Duration::from_secs(number.saturating_mul(60)),
+        _ => return Err(format!("invalid timeout `{}`, expected a duration like `10s`", value)),
+    };
+    if timeout == Duration::from_secs(0) {
+        return Err(String::from("the timeout must be longer than zero"));
+    }
+    Ok(timeout)
+}
+
+/// Whether an info string token is a compiler error code, like `E0308`.
+fn is_error_code(token: &str) -> bool {
+    token.len() == 5 && token.starts_with('E') && token[1..].chars().all(|c| c.is_ascii_digit())
 }
 
 struct CodeBlockInfo {
     is_rust: bool,
     should_panic: bool,
+    panic_message: Option<String>,
     ignore: bool,
     no_run: bool,
+    compile_fail: bool,
+    error_codes: Vec<String>,
+    edition: Option<String>,
+    // Features the example needs, without which it is ignored
+    features: Vec<String>,
+    timeout: Option<Duration>,
+    is_output: bool,
     is_old_template: bool,
     template: Option<String>,
 }
 
-fn emit_tests(config: &Config, suite: DocTestSuite) -> Result<(), IoError> {
+fn emit_tests(config: &Config, suite: DocTestSuite) -> Result<(), Error> {
     let mut out = String::new();
 
     // Test cases use the api from skeptic::rt
     out.push_str("extern crate skeptic;\n");
+    out.push_str(&create_build_config(config)?);
 
-    for doc_test in suite.doc_tests {
+    let mut merged: Vec<MergedExamples> = Vec::new();
+    for doc_test in &suite.doc_tests {
         for test in &doc_test.tests {
-            let test_string = {
-                if let Some(ref t) = test.template {
-                    let template = doc_test.templates.get(t).expect(&format!(
-                        "template {} not found for {}",
-                        t,
-                        doc_test.path.display()
-                    ));
-                    create_test_runner(config, &Some(template.to_string()), test)?
-                } else {
-                    create_test_runner(config, &doc_test.old_template, test)?
-                }
+            let template = if let Some(ref t) = test.template {
+                let template = doc_test.templates.get(t).ok_or_else(|| {
+                    ErrorKind::MissingTemplate(t.clone(), doc_test.path.clone(), test.line)
+                })?;
+                Some(template.to_string())
+            } else {
+                let template = doc_test
+                    .old_template
+                    .as_ref()
+                    .or(config.default_template.as_ref());
+                template.cloned()
             };
+
+            let mut merged_name = None;
+            if config.merge_examples {
+                let source = expand_template(
+                    template.as_ref().map_or("{}", |t| &t[..]),
+                    &create_test_input(&test.text),
+                );
+                if can_be_merged(test, &source) && missing_features(config, test).is_empty() {
+                    let index = match merged.iter().position(|m| m.edition == test.edition) {
+                        Some(index) => index,
+                        None => {
+                            merged.push(MergedExamples::new(test.edition.clone()));
+                            merged.len() - 1
+                        }
+                    };
+                    merged[index].push(&test.name, &source);
+                    merged_name = Some(format!("SKEPTIC_MERGED_{}", index));
+                }
+            }
+
+            let test_string =
+                create_test_runner(config, &doc_test.doc, &template, test, merged_name)?;
             out.push_str(&test_string);
         }
     }
+    for (index, merged) in merged.iter().enumerate() {
+        out.push_str(&format!(
+            "const SKEPTIC_MERGED_{}: &str = {:?};\n",
+            index,
+            merged.source()
+        ));
+    }
     write_if_contents_changed(&config.out_file, &out)
+        .chain_err(|| format!("failed to write {}", config.out_file.display()))
 }
 
 /// Just like Rustdoc, ignore a "#" sign at the beginning of a line of code.
@@ -437,58 +1117,331 @@ fn create_test_input(lines: &[String]) -> String {
         .collect()
 }
 
+/// Formats a template like `format!` would at runtime.
+fn expand_template(template: &str, text: &str) -> String {
+    let mut out = String::with_capacity(template.len() + text.len());
+    let mut chars = template.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.peek()) {
+            ('{', Some(&'}')) => {
+                chars.next();
+                out.push_str(text);
+            }
+            ('{', Some(&'{')) | ('}', Some(&'}')) => {
+                chars.next();
+                out.push(ch);
+            }
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Whether an example can be compiled as a module of a merged binary.
+fn can_be_merged(test: &Test, source: &str) -> bool {
+    // Only examples that are run are merged, and in the 2015 edition
+    // paths in `use` are relative to the crate root rather than the
+    // example's module
+    !test.ignore
+        && !test.no_run
+        && !test.compile_fail
+        && test.edition.as_ref().map_or(true, |edition| edition != "2015")
+        // Crate-level items and paths are those of the merged binary
+        && !source.contains("#![")
+        && !source.contains("extern crate")
+        && !source.contains("crate::")
+}
+
+/// Examples compiled together into one binary, as modules named after
+/// their tests.
+struct MergedExamples {
+    edition: Option<String>,
+    modules: String,
+    names: Vec<String>,
+}
+
+impl MergedExamples {
+    fn new(edition: Option<String>) -> MergedExamples {
+        MergedExamples {
+            edition,
+            modules: String::new(),
+            names: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, name: &str, source: &str) {
+        self.modules.push_str(&format!(
+            "mod {} {{\n{}\n\
+             pub fn skeptic_main() -> impl ::std::process::Termination {{ main() }}\n}}\n",
+            name,
+            source
+        ));
+        self.names.push(name.to_owned());
+    }
+
+    /// The source of the binary, whose `main` runs the example named by
+    /// the `SKEPTIC_EXAMPLE` environment variable.
+    fn source(&self) -> String {
+        let mut source = self.modules.clone();
+        source.push_str(
+            "fn main() -> ::std::process::ExitCode {\n\
+             \x20   fn run<T: ::std::process::Termination>(main: fn() -> T) \
+             -> ::std::process::ExitCode {\n\
+             \x20       main().report()\n\
+             \x20   }\n\
+             \x20   match ::std::env::var(\"SKEPTIC_EXAMPLE\").unwrap_or_default().as_str() {\n",
+        );
+        for name in &self.names {
+            source.push_str(&format!(
+                "        \"{0}\" => run({0}::skeptic_main),\n",
+                name
+            ));
+        }
+        source.push_str("        name => panic!(\"unknown example `{}`\", name),\n    }\n}\n");
+        source
+    }
+}
+
+/// Finds where the example is substituted into a template, as a line index
+/// and a column offset (in characters) into the formatted template.
+fn template_insertion_point(template: &str) -> (usize, usize) {
+    let mut line = 0;
+    let mut column = 0;
+    let mut chars = template.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.peek()) {
+            ('{', Some(&'}')) => break,
+            // Escaped braces format as a single character
+            ('{', Some(&'{')) | ('}', Some(&'}')) => {
+                chars.next();
+                column += 1;
+            }
+            ('\n', _) => {
+                line += 1;
+                column = 0;
+            }
+            _ => column += 1,
+        }
+    }
+    (line, column)
+}
+
+/// Describes how lines of the formatted test source map back to the
+/// markdown document, so that compiler diagnostics can point at the
+/// documentation rather than at the temporary file.
+///
+/// Returns the first line of the example within the test source and, for
+/// each of its lines, the column shift introduced by the template and by
+/// hidden-line removal.
+fn create_source_map(template: &str, lines: &[String]) -> (usize, Vec<isize>) {
+    let (template_line, template_column) = template_insertion_point(template);
+    let shifts = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let removed = line.chars().count() - clean_omitted_line(line).chars().count();
+            let inserted = if i == 0 { template_column } else { 0 };
+            removed as isize - inserted as isize
+        })
+        .collect();
+    // The formatted source begins with a newline, see create_test_runner.
+    (template_line + 2, shifts)
+}
+
+/// The settings all the tests of a build share, as a constant the tests
+/// refer to.
+fn create_build_config(config: &Config) -> Result<String, Error> {
+    let mut s: Vec<u8> = Vec::new();
+    writeln!(s, "const SKEPTIC_BUILD: skeptic::rt::BuildConfig<'static> = skeptic::rt::BuildConfig {{")?;
+    writeln!(s, "    root_dir: {:?},", path_to_str(&config.root_dir)?)?;
+    writeln!(s, "    out_dir: {:?},", path_to_str(&config.out_dir)?)?;
+    writeln!(s, "    target_triple: {:?},", config.target_triple)?;
+    writeln!(s, "    backend: skeptic::Backend::{:?},", config.backend)?;
+    writeln!(s, "    features: &{:?},", config.features)?;
+    writeln!(
+        s,
+        "    profile: skeptic::rt::Profile {{ opt_level: {:?}, debug_assertions: {} }},",
+        config.opt_level,
+        config.debug_assertions
+    )?;
+    writeln!(
+        s,
+        "    rustc_config: skeptic::rt::RustcConfig {{ wrapper: {:?}, workspace_wrapper: {:?}, flags: &{:?} }},",
+        config.rustc_wrapper,
+        config.rustc_workspace_wrapper,
+        config.rustflags
+    )?;
+    writeln!(s, "}};")?;
+    writeln!(s)?;
+
+    Ok(String::from_utf8(s).unwrap())
+}
+
 fn create_test_runner(
     config: &Config,
+    doc: &str,
     template: &Option<String>,
     test: &Test,
-) -> Result<String, IoError> {
+    merged: Option<String>,
+) -> Result<String, Error> {
 
     let template = template.clone().unwrap_or_else(|| String::from("{}"));
     let test_text = create_test_input(&test.text);
+    let (first_line, column_shifts) = create_source_map(&template, &test.text);
+    let source_map = format!(
+        "&skeptic::rt::SourceMap::new({:?}, {}, {}, &{:?})",
+        doc,
+        first_line,
+        test.line + 1,
+        column_shifts
+    );
+    let edition = match test.edition {
+        Some(ref edition) => format!("Some(\"{}\")", edition),
+        None => String::from("None"),
+    };
+    let expected_output = match test.expected_output {
+        Some((index, ref output)) => format!("Some(({}, {:?}))", index, output),
+        None => String::from("None"),
+    };
+
+    let timeout = match test.timeout.or(config.timeout) {
+        Some(timeout) => format!("Some(std::time::Duration::from_millis({}))", timeout.as_millis()),
+        None => String::from("None"),
+    };
+
+    let missing_features = missing_features(config, test);
 
     let mut s: Vec<u8> = Vec::new();
     if test.ignore {
         writeln!(s, "#[ignore]")?;
+    } else if !missing_features.is_empty() {
+        let features: Vec<String> = missing_features.iter().map(|f| format!("`{}`", f)).collect();
+        writeln!(
+            s,
+            "#[ignore = {:?}]",
+            format!(
+                "requires the {} feature{}",
+                features.join(", "),
+                if features.len() == 1 { "" } else { "s" }
+            )
+        )?;
     }
-    if test.should_panic {
+    // Panics of examples that are run are checked by `rt::test`, but
+    // `no_run,should_panic` examples are expected to fail to compile.
+    if test.should_panic && test.no_run {
         writeln!(s, "#[should_panic]")?;
     }
+    let should_panic = match test.panic_message {
+        Some(ref message) => format!("Some({:?})", message),
+        None if test.should_panic => String::from("Some(\"\")"),
+        None => String::from("None"),
+    };
 
     writeln!(s, "#[test] fn {}() {{", test.name)?;
     writeln!(
         s,
-        "    let s = &format!(r####\"{}{}\"####, r####\"{}\"####);",
-        "\n",
-        template,
+        "    let s = &format!({:?}, {:?});",
+        format!("\n{}", template),
         test_text
     )?;
 
-    // if we are not running, just compile the test without running it
-    if test.no_run {
-        writeln!(
-            s,
-            "    skeptic::rt::compile_test(r#\"{}\"#, r#\"{}\"#, r#\"{}\"#, s);",
-            config.root_dir.to_str().unwrap(),
-            config.out_dir.to_str().unwrap(),
-            config.target_triple
-        )?;
+    let mode = if test.compile_fail {
+        format!("skeptic::rt::Mode::CompileFail(&{:?})", test.error_codes)
+    } else if test.no_run {
+        // if we are not running, just compile the test without running it
+        String::from("skeptic::rt::Mode::Compile")
     } else {
-        writeln!(
-            s,
-            "    skeptic::rt::run_test(r#\"{}\"#, r#\"{}\"#, r#\"{}\"#, s);",
-            config.root_dir.to_str().unwrap(),
-            config.out_dir.to_str().unwrap(),
-            config.target_triple
-        )?;
-    }
+        format!(
+            "skeptic::rt::Mode::Run {{ expected_output: {}, should_panic: {} }}",
+            expected_output,
+            should_panic
+        )
+    };
+    let merged = match merged {
+        Some(merged) => format!(
+            "Some(skeptic::rt::Merged {{ source: {}, name: \"{}\" }})",
+            merged,
+            test.name
+        ),
+        None => String::from("None"),
+    };
 
+    writeln!(s, "    skeptic::rt::test(&skeptic::rt::TestSpec {{")?;
+    writeln!(s, "        build: &SKEPTIC_BUILD,")?;
+    writeln!(s, "        source: s,")?;
+    writeln!(s, "        source_map: {},", source_map)?;
+    writeln!(s, "        edition: {},", edition)?;
+    writeln!(s, "        mode: {},", mode)?;
+    writeln!(s, "        merged: {},", merged)?;
+    writeln!(s, "        env: &[],")?;
+    writeln!(s, "        forward_output: true,")?;
+    writeln!(s, "        output_limit: {},", config.output_limit)?;
+    writeln!(s, "        timeout: {},", timeout)?;
+    writeln!(s, "    }});")?;
     writeln!(s, "}}")?;
     writeln!(s, "")?;
 
     Ok(String::from_utf8(s).unwrap())
 }
 
-fn write_if_contents_changed(name: &Path, contents: &str) -> Result<(), IoError> {
+/// Replaces the contents of the `index`th `skt-output` code block of a
+/// markdown document, leaving everything else untouched.
+///
+/// Returns `None` if the document has no such block.
+fn replace_output_block(doc: &str, index: usize, output: &str) -> Option<String> {
+    let mut parser = Parser::new(doc);
+    let mut seen = 0;
+    let content_start = loop {
+        if let Event::Start(Tag::CodeBlock(ref info)) = parser.next()? {
+            let is_output = parse_code_block_info(info)
+                .map(|info| info.is_output)
+                .unwrap_or(false);
+            if is_output {
+                if seen == index {
+                    // The parser is now at the start of the block's first line
+                    break parser.get_offset();
+                }
+                seen += 1;
+            }
+        }
+    };
+
+    let fence_start = doc[..content_start.saturating_sub(1)]
+        .rfind('\n')
+        .map_or(0, |i| i + 1);
+    let fence_line = &doc[fence_start..content_start];
+    let fence = fence_line.trim_start();
+    let indent = &fence_line[..fence_line.len() - fence.len()];
+    let fence_char = fence.chars().next()?;
+    let fence_len = fence.chars().take_while(|&c| c == fence_char).count();
+    let newline = if fence_line.ends_with("\r\n") { "\r\n" } else { "\n" };
+
+    // The block ends at a closing fence at least as long as the opening one
+    let mut content_end = doc.len();
+    let mut line_start = content_start;
+    for line in doc[content_start..].split('\n') {
+        let trimmed = line.trim();
+        if trimmed.chars().take_while(|&c| c == fence_char).count() >= fence_len
+            && trimmed.chars().all(|c| c == fence_char)
+        {
+            content_end = line_start;
+            break;
+        }
+        line_start += line.len() + 1;
+    }
+
+    let mut out = String::with_capacity(doc.len() + output.len());
+    out.push_str(&doc[..content_start]);
+    for line in output.lines() {
+        out.push_str(indent);
+        out.push_str(line);
+        out.push_str(newline);
+    }
+    out.push_str(&doc[content_end..]);
+    Some(out)
+}
+
+fn write_if_contents_changed(name: &Path, contents: &str) -> io::Result<()> {
     // Can't open in write mode now as that would modify the last changed timestamp of the file
     match File::open(name) {
         Ok(mut file) => {
@@ -509,80 +1462,511 @@ fn write_if_contents_changed(name: &Path, contents: &str) -> Result<(), IoError>
 
 pub mod rt {
     extern crate serde_json;
-    extern crate cargo_metadata;
     extern crate walkdir;
 
-    use std::collections::HashMap;
-    use std::collections::hash_map::Entry;
-    use std::time::SystemTime;
+    use std::fmt;
+    use std::time::{Duration, Instant, SystemTime};
 
     use std::{self, env};
-    use std::fs::File;
-    use std::io::{self, Write};
+    use std::fs::{self, File};
+    use std::hash::{Hash, Hasher};
+    use std::io::{self, Read, Write};
     use std::path::{Path, PathBuf};
-    use std::process::Command;
+    use std::process::{self, Child, Command, ExitStatus, Stdio};
     use std::ffi::OsStr;
     use std::str::FromStr;
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
     use tempdir::TempDir;
+    use Backend;
 
     use self::walkdir::WalkDir;
     use self::serde_json::Value;
 
     error_chain! {
-        errors { Fingerprint }
-        foreign_links {
-            Io(std::io::Error);
-            Metadata(cargo_metadata::Error);
+        errors {
+            Fingerprint
+            AmbiguousDependency(lib_name: String, versions: Vec<String>) {
+                description("ambiguous dependency")
+                display(
+                    "cannot tell which build of `{}` to use, as versions {} are all dependencies",
+                    lib_name,
+                    versions.join(", ")
+                )
+            }
+            AmbiguousBuild(lib_name: String, rlibs: Vec<String>) {
+                description("ambiguous build of a dependency")
+                display(
+                    "cannot tell which build of `{}` to use, as it was built more than once: {}",
+                    lib_name,
+                    rlibs.join(", ")
+                )
+            }
+        }
+        foreign_links {
+            Io(std::io::Error);
             Json(serde_json::Error);
         }
     }
 
+    /// The settings of the profile the tests are built with, which examples
+    /// are compiled with too.
+    ///
+    /// Instances are created by the generated test code.
+    #[derive(Clone, Copy, Debug)]
+    pub struct Profile<'a> {
+        /// The `opt-level` of the profile, as in `OPT_LEVEL`.
+        pub opt_level: &'a str,
+        /// Whether `debug-assertions` are enabled.
+        pub debug_assertions: bool,
+    }
+
+    /// How cargo runs rustc for the package, which examples are compiled
+    /// like, so that they can use the libraries it compiled.
+    ///
+    /// Instances are created by the generated test code.
+    #[derive(Clone, Copy, Debug)]
+    pub struct RustcConfig<'a> {
+        /// The program rustc is run through, as in `RUSTC_WRAPPER`.
+        pub wrapper: Option<&'a str>,
+        /// The program rustc is run through for the members of the
+        /// workspace, as in `RUSTC_WORKSPACE_WRAPPER`, after `wrapper`.
+        pub workspace_wrapper: Option<&'a str>,
+        /// The flags passed to rustc, as in `CARGO_ENCODED_RUSTFLAGS`.
+        pub flags: &'a [&'a str],
+    }
+
+    /// How the examples of a package are compiled, which is the same for
+    /// all of them.
+    ///
+    /// An instance is created by the generated test code, and shared by its
+    /// tests.
+    #[derive(Clone, Copy, Debug)]
+    pub struct BuildConfig<'a> {
+        /// The directory of the package the examples are from.
+        pub root_dir: &'a str,
+        /// The `OUT_DIR` of the package's build script.
+        pub out_dir: &'a str,
+        /// The target the examples are compiled for, as in `TARGET`.
+        pub target_triple: &'a str,
+        pub backend: Backend,
+        /// The features enabled for the tests, as in `CARGO_FEATURE_*`.
+        pub features: &'a [&'a str],
+        pub profile: Profile<'a>,
+        pub rustc_config: RustcConfig<'a>,
+    }
+
+    /// What is expected of an example.
+    #[derive(Clone, Copy, Debug)]
+    pub enum Mode<'a> {
+        /// The example must compile. It is not run.
+        Compile,
+        /// The example must fail to compile, with each of the given error
+        /// codes.
+        CompileFail(&'a [&'a str]),
+        /// The example must compile and run successfully.
+        Run {
+            /// The output the example must print, and the index of the
+            /// `skt-output` block of its document it is from.
+            expected_output: Option<(usize, &'a str)>,
+            /// If given, the example must panic instead, and its stderr must
+            /// contain the message. An empty message accepts any panic.
+            should_panic: Option<&'a str>,
+        },
+    }
+
+    /// A binary that several examples are compiled into, to save compiling
+    /// them one by one.
+    #[derive(Clone, Copy, Debug)]
+    pub struct Merged<'a> {
+        /// The source of the binary.
+        pub source: &'a str,
+        /// The name the binary knows the example by.
+        pub name: &'a str,
+    }
+
+    /// An example to run, and what is expected of it.
+    #[derive(Clone, Copy, Debug)]
+    pub struct TestSpec<'a> {
+        pub build: &'a BuildConfig<'a>,
+        /// The example, formatted with its template.
+        pub source: &'a str,
+        pub source_map: &'a SourceMap,
+        /// The edition of the example, if not that of the package.
+        pub edition: Option<&'a str>,
+        pub mode: Mode<'a>,
+        /// A binary the example is compiled into, which it is run from if
+        /// the binary compiles.
+        pub merged: Option<Merged<'a>>,
+        /// Environment variables to set when running the example.
+        pub env: &'a [(&'a str, &'a str)],
+        /// Whether to pass the output of the example on to the stdout and
+        /// stderr of this process while it runs.
+        pub forward_output: bool,
+        /// How many bytes of each of the example's stdout and stderr to keep
+        /// in the outcome.
+        pub output_limit: usize,
+        /// How long the example may run before it is killed, along with the
+        /// processes it started.
+        pub timeout: Option<Duration>,
+    }
+
+    /// The default `output_limit` of a `TestSpec`.
+    pub const DEFAULT_OUTPUT_LIMIT: usize = 16 * 1024;
+
+    /// What happened when an example was compiled and run.
+    #[derive(Debug, Default)]
+    pub struct Outcome {
+        /// Why the example did not behave as expected, or `None` if it did.
+        pub failure: Option<String>,
+        /// The messages of the compiler.
+        pub diagnostics: Vec<Diagnostic>,
+        /// What the compiler printed other than its messages.
+        pub compile_output: String,
+        /// How the example exited, if it was run.
+        pub status: Option<ExitStatus>,
+        /// The stdout of the example, up to the output limit. Invalid UTF-8
+        /// is replaced with `U+FFFD`.
+        pub stdout: String,
+        /// The number of bytes of stdout beyond the output limit.
+        pub stdout_omitted: usize,
+        /// The stderr of the example, like `stdout`.
+        pub stderr: String,
+        pub stderr_omitted: usize,
+        /// How long compiling the example took, which is zero if a binary
+        /// compiled earlier was used.
+        pub compile_time: Duration,
+        /// How long the example ran, if it was run.
+        pub run_time: Option<Duration>,
+        /// Whether the example was killed for running longer than its
+        /// timeout.
+        pub timed_out: bool,
+        /// The directory the example's files were kept in, as asked for
+        /// with `SKEPTIC_KEEP_TEMP`.
+        pub kept_dir: Option<PathBuf>,
+    }
+
+    /// Maps lines of a formatted test back to the markdown document the
+    /// example was taken from.
+    ///
+    /// Instances are created by the generated test code.
+    #[derive(Clone, Debug)]
+    pub struct SourceMap {
+        file: String,
+        first_line: usize,
+        doc_first_line: usize,
+        column_shifts: Vec<isize>,
+    }
+
+    impl SourceMap {
+        /// Creates a map for an example whose first line is `first_line` of
+        /// the formatted test and `doc_first_line` of `file`.
+        ///
+        /// `column_shifts` has one entry per line of the example, giving the
+        /// number of columns to add to a test column to get the document
+        /// column.
+        pub fn new(
+            file: &str,
+            first_line: usize,
+            doc_first_line: usize,
+            column_shifts: &[isize],
+        ) -> SourceMap {
+            SourceMap {
+                file: file.to_owned(),
+                first_line,
+                doc_first_line,
+                column_shifts: column_shifts.to_vec(),
+            }
+        }
+
+        /// Translates a 1-based line and column of the formatted test into
+        /// a location in the document, if it lies within the example
+        /// rather than in its template.
+        pub fn locate(&self, line: usize, column: usize) -> Option<DocLocation> {
+            if line < self.first_line {
+                return None;
+            }
+            let index = line - self.first_line;
+            let shift = *self.column_shifts.get(index)?;
+            let column = std::cmp::max(column as isize + shift, 1) as usize;
+            Some(DocLocation {
+                file: self.file.clone(),
+                line: self.doc_first_line + index,
+                column,
+            })
+        }
+
+        /// The location of the first line of the example.
+        pub fn start(&self) -> DocLocation {
+            DocLocation {
+                file: self.file.clone(),
+                line: self.doc_first_line,
+                column: 1,
+            }
+        }
+    }
+
+    /// A position within a markdown document.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct DocLocation {
+        pub file: String,
+        pub line: usize,
+        pub column: usize,
+    }
+
+    impl fmt::Display for DocLocation {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}:{}:{}", self.file, self.line, self.column)
+        }
+    }
+
+    /// A compiler message parsed from rustc's JSON output.
+    #[derive(Clone, Debug)]
+    pub struct Diagnostic {
+        /// The level of the message, like `error` or `warning`.
+        pub level: String,
+        /// The error code, like `E0308`.
+        pub code: Option<String>,
+        /// The message as rustc prints it, pointing into the document.
+        pub rendered: String,
+        /// Where in the document the message points to.
+        pub location: Option<DocLocation>,
+    }
+
+    impl Diagnostic {
+        fn from_json(line: &str, source_map: &SourceMap) -> Option<Diagnostic> {
+            let parsed: Value = serde_json::from_str(line).ok()?;
+            let rendered = parsed["rendered"].as_str()?;
+            let location = parsed["spans"]
+                .as_array()
+                .and_then(|spans| spans.iter().find(|span| span["is_primary"] == true))
+                .and_then(|span| {
+                    let line = span["line_start"].as_u64()?;
+                    let column = span["column_start"].as_u64()?;
+                    source_map.locate(line as usize, column as usize)
+                });
+
+            Some(Diagnostic {
+                level: parsed["level"].as_str().unwrap_or("").to_owned(),
+                code: parsed["code"]["code"].as_str().map(|c| c.to_owned()),
+                rendered: remap_rendered(rendered, source_map),
+                location,
+            })
+        }
+    }
+
+    /// Rewrites a rendered diagnostic that points into the formatted test
+    /// so that it points into the markdown document instead: the
+    /// `--> file:line:col` markers, and the line numbers in the gutter of
+    /// the snippets that follow them, which is resized to fit the new
+    /// numbers.
+    fn remap_rendered(rendered: &str, source_map: &SourceMap) -> String {
+        // The markers are indented by the width of the gutter
+        let width = rendered
+            .lines()
+            .map(|line| (line, line.trim_start()))
+            .find(|&(_, trimmed)| trimmed.starts_with("--> "))
+            .map(|(line, trimmed)| line.len() - trimmed.len());
+        let width = match width {
+            Some(width) => width,
+            None => return rendered.to_owned(),
+        };
+
+        // Each line, with its gutter split off if it has one
+        let mut lines: Vec<(Option<String>, String)> = Vec::new();
+        let mut in_example = false;
+        for line in rendered.lines() {
+            let (number, rest) = match split_gutter(line, width) {
+                Some(split) => split,
+                None => {
+                    lines.push((None, line.to_owned()));
+                    continue;
+                }
+            };
+            let mut rest = rest.to_owned();
+            if rest.starts_with("--> ") || rest.starts_with("::: ") {
+                // The snippets that follow are from the marker's file
+                let location = locate_marker(&rest[4..], source_map);
+                in_example = location.is_some();
+                if let Some(location) = location {
+                    rest = format!("{}{}", &rest[..4], location);
+                }
+            }
+            let number = match usize::from_str(number) {
+                Ok(line) if in_example => {
+                    source_map.locate(line, 1).map_or(line, |l| l.line).to_string()
+                }
+                _ => number.to_owned(),
+            };
+            lines.push((Some(number), rest));
+        }
+
+        let width = lines
+            .iter()
+            .filter_map(|(number, _)| number.as_ref().map(|n| n.len()))
+            .max()
+            .unwrap_or(width);
+        let mut out = String::new();
+        for (number, rest) in lines {
+            match number {
+                Some(number) => {
+                    out.push_str(&format!("{:>width$}{}\n", number, rest, width = width))
+                }
+                None => {
+                    out.push_str(&rest);
+                    out.push('\n');
+                }
+            }
+        }
+        out
+    }
+
+    // Splits a line of a rendered diagnostic into the line number in its
+    // gutter, which is empty for lines without one, and the rest, if the
+    // line has a gutter of the given width
+    fn split_gutter(line: &str, width: usize) -> Option<(&str, &str)> {
+        if line.len() < width || !line.is_char_boundary(width) {
+            return None;
+        }
+        let (gutter, rest) = line.split_at(width);
+        let number = gutter.trim_start();
+        let is_gutter = gutter[..width - number.len()].chars().all(|c| c == ' ')
+            && number.chars().all(|c| c.is_ascii_digit());
+        let has_gutter = rest.starts_with(" |")
+            || rest.starts_with(" =")
+            || rest.starts_with("--> ")
+            || rest.starts_with("::: ");
+        if is_gutter && has_gutter {
+            Some((number, rest))
+        } else {
+            None
+        }
+    }
+
+    // The location in the document a marker's `file:line:col` points to,
+    // if it points into the example
+    fn locate_marker(target: &str, source_map: &SourceMap) -> Option<DocLocation> {
+        let mut parts = target.rsplitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(column), Some(line), Some(file)) if Path::new(file).ends_with("test.rs") => {
+                match (usize::from_str(line), usize::from_str(column)) {
+                    (Ok(line), Ok(column)) => source_map.locate(line, column),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
     #[derive(Clone, Copy)]
     enum CompileType {
         Full,
         Check,
     }
 
-    // An iterator over the root dependencies in a lockfile
+    // A dependency of the package as resolved in the lockfile
+    #[derive(Debug)]
+    struct LockedDep {
+        // The name the package's code refers to the crate by, which differs
+        // from the library's name when the dependency is renamed
+        extern_name: String,
+        // The name of the library, which cargo names its files after
+        lib_name: String,
+        version: String,
+        package_id: String,
+    }
+
+    // The root dependencies in a lockfile
     #[derive(Debug)]
     struct LockedDeps {
-        dependencies: Vec<String>,
+        dependencies: Vec<LockedDep>,
     }
 
     impl LockedDeps {
-        fn from_path<P: AsRef<Path>>(pth: P) -> Result<LockedDeps> {
-            let pth = pth.as_ref().join("Cargo.toml");
-            let metadata = cargo_metadata::metadata_deps(Some(&pth), true)?;
-            let workspace_members:Vec<String> = metadata.workspace_members
+        // The direct dependencies of the package in `root_dir`, and the
+        // package itself. If the package can't be found, those of all the
+        // workspace members.
+        fn from_metadata(metadata: &Value, root_dir: &Path) -> Result<LockedDeps> {
+            let packages = metadata["packages"].as_array().ok_or("Missing package metadata")?;
+            let package_by_id = |id: &str| packages.iter().find(|package| package["id"] == id);
+            let workspace_members: Vec<&str> = metadata["workspace_members"]
+                .as_array()
                 .into_iter()
-                .map(|workspace| {format!("{} {} ({})", workspace.name(), workspace.version(), workspace.url())})
+                .flat_map(|members| members.iter())
+                .filter_map(|member| member.as_str())
                 .collect();
-            let deps = metadata.resolve.ok_or("Missing dependency metadata")?
-                .nodes
-                .into_iter()
-                .filter(|node| workspace_members.contains(&node.id))
-                .flat_map(|node| node.dependencies.into_iter())
-                .chain(workspace_members.clone());
+            // Other versions of the dependencies may be in the graph too, and
+            // must not be mistaken for those the package uses
+            let roots = match find_package(metadata, root_dir).and_then(|p| p["id"].as_str()) {
+                Some(id) => vec![id],
+                None => workspace_members,
+            };
+            let nodes = metadata["resolve"]["nodes"]
+                .as_array()
+                .ok_or("Missing dependency metadata")?;
+
+            let mut dependencies = Vec::new();
+            let mut push = |extern_name: Option<&str>, id: &str| {
+                let package = match package_by_id(id) {
+                    Some(package) => package,
+                    None => return,
+                };
+                let lib_name = match library_name(package) {
+                    Some(lib_name) => lib_name,
+                    None => return,
+                };
+                if dependencies.iter().any(|dep: &LockedDep| dep.package_id == id) {
+                    return;
+                }
+                dependencies.push(LockedDep {
+                    extern_name: extern_name.map_or_else(|| lib_name.clone(), |n| n.replace("-", "_")),
+                    lib_name,
+                    version: package["version"].as_str().unwrap_or("").to_owned(),
+                    package_id: id.to_owned(),
+                });
+            };
 
-            Ok(LockedDeps { dependencies: deps.collect() })
-        }
-    }
+            for node in nodes.iter().filter(|node| {
+                node["id"].as_str().map_or(false, |id| roots.contains(&id))
+            }) {
+                // `deps` carries the names the dependencies are used under
+                for dep in node["deps"].as_array().into_iter().flat_map(|deps| deps.iter()) {
+                    // Dependencies only of build scripts are not for examples
+                    let build_only = dep["dep_kinds"].as_array().map_or(false, |kinds| {
+                        !kinds.is_empty() && kinds.iter().all(|kind| kind["kind"] == "build")
+                    });
+                    if let (false, Some(id)) = (build_only, dep["pkg"].as_str()) {
+                        push(dep["name"].as_str(), id);
+                    }
+                }
+            }
+            for root in &roots {
+                push(None, root);
+            }
 
-    impl Iterator for LockedDeps {
-        type Item = (String, String);
+            Ok(LockedDeps { dependencies })
+        }
 
-        fn next(&mut self) -> Option<(String, String)> {
-            self.dependencies.pop().and_then(|val| {
-                let mut it = val.split_whitespace();
+        fn contains(&self, package_id: &str) -> Option<&LockedDep> {
+            self.dependencies.iter().find(|dep| dep.package_id == package_id)
+        }
+    }
 
-                match (it.next(), it.next()) {
-                    (Some(name), Some(val)) => {
-                        Some((name.replace("-", "_").to_owned(), val.to_owned()))
-                    }
-                    _ => None,
-                }
+    // The name of a package's library, if it has one
+    fn library_name(package: &Value) -> Option<String> {
+        package["targets"]
+            .as_array()?
+            .iter()
+            .find(|target| {
+                target["kind"].as_array().map_or(false, |kinds| {
+                    kinds.iter().any(|kind| {
+                        kind == "lib" || kind == "rlib" || kind == "dylib" || kind == "proc-macro"
+                    })
+                })
             })
-        }
+            .and_then(|target| target["name"].as_str())
+            .map(|name| name.replace("-", "_"))
     }
 
     #[derive(Debug)]
@@ -590,7 +1974,6 @@ pub mod rt {
         libname: String,
         version: Option<String>, // version might not be present on path or vcs deps
         rlib: PathBuf,
-        mtime: SystemTime,
     }
 
     fn guess_ext(mut pth: PathBuf, exts: &[&str]) -> Result<PathBuf> {
@@ -618,7 +2001,16 @@ pub mod rt {
             let mut captures = fname.splitn(3, '-');
             captures.next();
             let libname = captures.next().ok_or(ErrorKind::Fingerprint)?;
-            let hash = captures.next().ok_or(ErrorKind::Fingerprint)?;
+            // Newer cargo only keeps the hash in the directory name,
+            // `.fingerprint/<package>-<hash>/lib-<libname>.json`
+            let hash = match captures.next() {
+                Some(hash) => hash,
+                None => pth.parent()
+                    .and_then(Path::file_name)
+                    .and_then(OsStr::to_str)
+                    .and_then(|dir| dir.rsplit('-').next())
+                    .ok_or(ErrorKind::Fingerprint)?,
+            };
 
             let mut rlib = PathBuf::from(pth);
             rlib.pop();
@@ -628,7 +2020,6 @@ pub mod rt {
             rlib = guess_ext(rlib, &["rlib", "so", "dylib", "dll"])?;
 
             let file = File::open(pth)?;
-            let mtime = file.metadata()?.modified()?;
             let parsed: Value = serde_json::from_reader(file)?;
             let vers = parsed["local"]["Precalculated"]
                 .as_str()
@@ -640,219 +2031,2203 @@ pub mod rt {
                 libname: libname.to_owned(),
                 version: vers,
                 rlib: rlib,
-                mtime: mtime,
             })
         }
-
-        fn name(&self) -> String {
-            self.libname.clone()
+
+        fn name(&self) -> String {
+            self.libname.clone()
+        }
+
+        fn version(&self) -> Option<String> {
+            self.version.clone()
+        }
+    }
+
+    // What examples need to know about the package being tested. It is the
+    // same for all the examples of a test binary, which share it, as running
+    // `cargo metadata` and walking the fingerprints for each is slow.
+    #[derive(Debug)]
+    struct PackageInfo {
+        root_dir: PathBuf,
+        target_dir: PathBuf,
+        // The features enabled for the tests, as given in `BuildConfig`
+        enabled_features: Vec<String>,
+        // The edition of the package, if it could be found
+        edition: Option<String>,
+        // The features the package declares
+        features: Vec<String>,
+        dependencies: Vec<Dependency>,
+        build_output: BuildOutput,
+    }
+
+    // A library examples can use, passed to rustc with `--extern`
+    #[derive(Debug)]
+    struct Dependency {
+        libname: String,
+        rlib: PathBuf,
+    }
+
+    // What build scripts told cargo to pass to rustc when compiling the
+    // package, which examples need as well. Like cargo, only the search
+    // paths of dependencies are used, as the rest is about their own code.
+    #[derive(Debug, Default)]
+    struct BuildOutput {
+        link_libs: Vec<String>,
+        link_search: Vec<String>,
+        cfgs: Vec<String>,
+        env: Vec<(String, String)>,
+    }
+
+    impl BuildOutput {
+        // Reads the `output` files in the build script directories, which
+        // are given along with whether they belong to the package itself
+        fn read(build_dirs: &[(PathBuf, bool)]) -> BuildOutput {
+            let mut build_output = BuildOutput::default();
+            for &(ref dir, own) in build_dirs {
+                let mut output = String::new();
+                if let Ok(mut file) = File::open(dir.join("output")) {
+                    if file.read_to_string(&mut output).is_ok() {
+                        build_output.parse(&output, own);
+                    }
+                }
+            }
+            build_output
+        }
+
+        fn parse(&mut self, output: &str, own: bool) {
+            for line in output.lines() {
+                let line = line.trim_end();
+                // `cargo::` is the newer form of the instructions
+                let instruction = match line
+                    .strip_prefix("cargo::")
+                    .or_else(|| line.strip_prefix("cargo:"))
+                {
+                    Some(instruction) => instruction,
+                    None => continue,
+                };
+                let (key, value) = match instruction.find('=') {
+                    Some(i) => (&instruction[..i], &instruction[i + 1..]),
+                    None => continue,
+                };
+                match key {
+                    "rustc-link-search" => self.push_search(value),
+                    "rustc-link-lib" if own => self.link_libs.push(value.to_owned()),
+                    "rustc-cfg" if own => self.cfgs.push(value.to_owned()),
+                    "rustc-env" if own => {
+                        if let Some(i) = value.find('=') {
+                            self.env.push((value[..i].to_owned(), value[i + 1..].to_owned()));
+                        }
+                    }
+                    "rustc-flags" => {
+                        let mut flags = value.split_whitespace();
+                        while let Some(flag) = flags.next() {
+                            match (flag, flags.next()) {
+                                ("-L", Some(path)) => self.push_search(path),
+                                ("-l", Some(lib)) if own => self.link_libs.push(lib.to_owned()),
+                                _ => (),
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        fn push_search(&mut self, path: &str) {
+            if !self.link_search.iter().any(|p| p == path) {
+                self.link_search.push(path.to_owned());
+            }
+        }
+
+        fn rustc_args(&self) -> Vec<String> {
+            let mut args = Vec::new();
+            for path in &self.link_search {
+                args.push(String::from("-L"));
+                args.push(path.clone());
+            }
+            for lib in &self.link_libs {
+                args.push(String::from("-l"));
+                args.push(lib.clone());
+            }
+            for cfg in &self.cfgs {
+                args.push(String::from("--cfg"));
+                args.push(cfg.clone());
+            }
+            args
+        }
+    }
+
+    static PACKAGE_INFO: Mutex<Vec<Arc<PackageInfo>>> = Mutex::new(Vec::new());
+
+    impl PackageInfo {
+        // Returns the information about the package the examples are from,
+        // reading it only the first time it is asked for
+        fn get(build: &BuildConfig) -> Result<Arc<PackageInfo>> {
+            let root_dir = Path::new(build.root_dir);
+            let target_dir = target_dir(build.out_dir);
+            // Holding the lock while reading makes tests running in parallel
+            // wait for the first one instead of all reading it too
+            let mut cache = PACKAGE_INFO.lock().unwrap_or_else(|e| e.into_inner());
+            let cached = cache
+                .iter()
+                .find(|info| {
+                    info.root_dir == root_dir
+                        && info.target_dir == target_dir
+                        && info.enabled_features.iter().eq(build.features)
+                })
+                .cloned();
+            if let Some(info) = cached {
+                return Ok(info);
+            }
+            let info = Arc::new(PackageInfo::read(build, &target_dir)?);
+            cache.push(info.clone());
+            Ok(info)
+        }
+
+        fn read(build: &BuildConfig, target_dir: &Path) -> Result<PackageInfo> {
+            let (root_dir, enabled_features) = (Path::new(build.root_dir), build.features);
+            let metadata = read_metadata(root_dir, true).or_else(|_| {
+                // could not find Cargo.lock in $CARGO_MAINFEST_DIR
+                // try relative to target_dir
+                let mut root_dir = PathBuf::from(target_dir);
+                root_dir.pop();
+                root_dir.pop();
+                read_metadata(&root_dir, true)
+            })?;
+            let edition = get_edition(&metadata, root_dir);
+            let lock = LockedDeps::from_metadata(&metadata, root_dir)?;
+            let package = find_package(&metadata, root_dir);
+            let features = package.map(declared_features).unwrap_or_default();
+            // Only fall back to guessing from cargo's internal files if cargo
+            // can't tell
+            let artifacts = package
+                .and_then(current_test_target)
+                .ok_or_else(|| Error::from("cannot tell which tests run the examples"))
+                .and_then(|test_target| {
+                    get_artifact_dependencies(
+                        &lock,
+                        package.and_then(|p| p["id"].as_str()),
+                        &test_target,
+                        &feature_names(&features, enabled_features),
+                        root_dir,
+                        target_dir,
+                        build.target_triple,
+                    )
+                });
+            let (dependencies, build_dirs) = match artifacts {
+                Ok(artifacts) => artifacts,
+                Err(_) => (
+                    get_rlib_dependencies(&lock, target_dir, build.target_triple)?,
+                    get_build_dirs(package.and_then(|p| p["name"].as_str()), target_dir),
+                ),
+            };
+
+            Ok(PackageInfo {
+                root_dir: root_dir.to_owned(),
+                target_dir: target_dir.to_owned(),
+                enabled_features: enabled_features.iter().map(|f| f.to_string()).collect(),
+                edition,
+                features,
+                dependencies,
+                build_output: BuildOutput::read(&build_dirs),
+            })
+        }
+    }
+
+    // The output of `cargo metadata` for the package in `root_dir`. The
+    // format is read directly, as it describes renamed dependencies
+    // unlike older versions of the `cargo_metadata` crate.
+    fn read_metadata(root_dir: &Path, with_deps: bool) -> Result<Value> {
+        let cargo = env::var("CARGO").unwrap_or_else(|_| String::from("cargo"));
+        let manifest_path = root_dir.join("Cargo.toml");
+        let mut cmd = Command::new(cargo);
+        cmd.arg("metadata")
+            .arg("--format-version=1")
+            .arg("--manifest-path")
+            .arg(&manifest_path);
+        if !with_deps {
+            cmd.arg("--no-deps");
+        }
+        let output = cmd.output()?;
+        if !output.status.success() {
+            bail!("failed to read {}", manifest_path.display());
+        }
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+
+    // The package whose manifest lives in `root_dir`, i.e. the package
+    // that owns the build script generating the tests
+    fn find_package<'a>(metadata: &'a Value, root_dir: &Path) -> Option<&'a Value> {
+        let path = root_dir.join("Cargo.toml").canonicalize().ok()?;
+        metadata["packages"].as_array()?.iter().find(|package| {
+            package["manifest_path"]
+                .as_str()
+                .and_then(|manifest_path| Path::new(manifest_path).canonicalize().ok())
+                .map_or(false, |manifest_path| manifest_path == path)
+        })
+    }
+
+    fn declared_features(package: &Value) -> Vec<String> {
+        package["features"]
+            .as_object()
+            .map_or_else(Vec::new, |features| features.keys().cloned().collect())
+    }
+
+    // The names of the enabled features, which are given as in the
+    // `CARGO_FEATURE_*` variables cargo sets for build scripts: lowercase,
+    // and with `_` instead of `-`. Features the package does not declare,
+    // like `default` when it has no default features, are left out.
+    fn feature_names(declared: &[String], enabled: &[&str]) -> Vec<String> {
+        enabled
+            .iter()
+            .filter_map(|&enabled| {
+                declared
+                    .iter()
+                    .find(|declared| declared.to_lowercase().replace("-", "_") == enabled)
+                    .cloned()
+            })
+            .collect()
+    }
+
+    // The name of the test target whose binary is running, which is named
+    // `<target name>-<hash>`, with `-` in the target name replaced by `_`
+    fn current_test_target(package: &Value) -> Option<String> {
+        let exe = env::current_exe().ok()?;
+        let binary = exe.file_stem()?.to_str()?.rsplit_once('-')?.0.to_owned();
+        package["targets"]
+            .as_array()?
+            .iter()
+            .filter(|target| target["kind"].as_array().map_or(false, |kinds| kinds.iter().any(|k| k == "test")))
+            .filter_map(|target| target["name"].as_str())
+            .find(|name| name.replace("-", "_") == binary)
+            .map(|name| name.to_owned())
+    }
+
+    fn get_edition(metadata: &Value, root_dir: &Path) -> Option<String> {
+        let package = find_package(metadata, root_dir)?;
+        Some(package["edition"].as_str().unwrap_or("2015").to_owned())
+    }
+
+    // Retrieve the exact dependencies for a given build by
+    // cross-referencing the lockfile with the fingerprint file
+    fn get_rlib_dependencies(lock: &LockedDeps, target_dir: &Path, target_triple: &str) -> Result<Vec<Dependency>> {
+        let mut fingerprints: Vec<Fingerprint> = WalkDir::new(target_dir.join(".fingerprint/"))
+            .into_iter()
+            .filter_map(|v| v.ok())
+            .filter_map(|v| Fingerprint::from_path(v.path()).ok())
+            .filter(|finger| finger.rlib.exists())
+            .collect();
+        // When cross-compiling, procedural macros are built for the host,
+        // next to the build scripts. Of those, only the macros are used,
+        // which are dynamic libraries rather than rlibs.
+        if let Some(host_dir) = host_target_dir(target_dir, target_triple) {
+            fingerprints.extend(
+                WalkDir::new(host_dir.join(".fingerprint/"))
+                    .into_iter()
+                    .filter_map(|v| v.ok())
+                    .filter_map(|v| Fingerprint::from_path(v.path()).ok())
+                    .filter(|finger| finger.rlib.exists())
+                    .filter(|finger| finger.rlib.extension().map_or(false, |ext| ext != "rlib")),
+            );
+        }
+
+        let mut deps = Vec::new();
+        for locked in &lock.dependencies {
+            // Fingerprints are named after the library, which several
+            // versions of a crate share
+            let versions: Vec<String> = lock
+                .dependencies
+                .iter()
+                .filter(|dep| dep.lib_name == locked.lib_name)
+                .map(|dep| dep.version.clone())
+                .collect();
+            let mut found: Vec<&Fingerprint> = Vec::new();
+            for finger in fingerprints.iter().filter(|finger| finger.name() == locked.lib_name) {
+                match finger.version() {
+                    Some(ref version) if *version != locked.version => continue,
+                    // Unversioned builds could be of any of the versions
+                    None if versions.len() > 1 => {
+                        bail!(ErrorKind::AmbiguousDependency(locked.lib_name.clone(), versions))
+                    }
+                    _ => (),
+                }
+                if found.iter().all(|found| found.rlib != finger.rlib) {
+                    found.push(finger);
+                }
+            }
+            // Builds of the same version with other settings, like other
+            // features, can't be told apart from the one of the tests
+            if found.len() > 1 {
+                let rlibs = found.iter().map(|finger| finger.rlib.display().to_string()).collect();
+                bail!(ErrorKind::AmbiguousBuild(locked.lib_name.clone(), rlibs))
+            }
+            if let Some(finger) = found.pop() {
+                deps.push(Dependency {
+                    libname: locked.extern_name.clone(),
+                    rlib: finger.rlib.clone(),
+                });
+            }
+        }
+        Ok(deps)
+    }
+
+    // The output directories of build scripts, along with whether each is
+    // the package's own
+    type BuildDirs = Vec<(PathBuf, bool)>;
+
+    // The directories of the build scripts that ran, guessed from their
+    // names as found in the target directory. Of the package's own build
+    // script, only the latest run is used.
+    fn get_build_dirs(package_name: Option<&str>, target_dir: &Path) -> BuildDirs {
+        let mut build_dirs = Vec::new();
+        let mut own: Option<(PathBuf, SystemTime)> = None;
+        let entries = match fs::read_dir(target_dir.join("build")) {
+            Ok(entries) => entries,
+            Err(_) => return build_dirs,
+        };
+        for dir in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            let mtime = match fs::metadata(dir.join("output")).and_then(|m| m.modified()) {
+                Ok(mtime) => mtime,
+                Err(_) => continue,
+            };
+            // Named `<package name>-<hash>`
+            let name = dir
+                .file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|name| name.rsplit_once('-'))
+                .map(|(name, _)| name.to_owned());
+            if name.is_some() && name.as_ref().map(|n| &n[..]) == package_name {
+                if own.as_ref().map_or(true, |&(_, latest)| latest < mtime) {
+                    own = Some((dir, mtime));
+                }
+            } else {
+                build_dirs.push((dir, false));
+            }
+        }
+        build_dirs.extend(own.map(|(dir, _)| (dir, true)));
+        build_dirs
+    }
+
+    // Cargo runs this in place of rustc when it reports the artifacts of the
+    // tests. It only passes cargo's questions about the compiler on to rustc
+    // and refuses to compile anything, so that tests that are not fresh fail
+    // to build rather than being built again in the target directory.
+    const RUSTC_GUARD: &str = r#"use std::env;
+use std::process::{self, Command};
+
+fn main() {
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    let is_query = args.iter().any(|arg| {
+        arg.to_str().map_or(false, |arg| arg == "-vV" || arg.starts_with("--print"))
+    });
+    if !is_query {
+        eprintln!("the tests running the examples are not fresh");
+        process::exit(1);
+    }
+    let status = Command::new("rustc").args(&args).status();
+    process::exit(status.ok().and_then(|status| status.code()).unwrap_or(1));
+}
+"#;
+
+    // The guard is compiled once, and kept with the compiled examples
+    fn rustc_guard(target_dir: &Path) -> Result<PathBuf> {
+        let rustc = rustc();
+        let mut hasher = StableHasher::default();
+        RUSTC_GUARD.hash(&mut hasher);
+        rustc_version(&rustc)?.hash(&mut hasher);
+        let guard_path = target_dir
+            .join("skeptic-cache")
+            .join(format!("rustc-guard-{:016x}.exe", hasher.finish()));
+        if guard_path.exists() {
+            return Ok(guard_path);
+        }
+
+        let dir = TempDir::new("rust-skeptic")?;
+        let source_path = dir.path().join("guard.rs");
+        let binary_path = dir.path().join("guard.exe");
+        File::create(&source_path)?.write_all(RUSTC_GUARD.as_bytes())?;
+        let output = Command::new(&rustc)
+            .arg(&source_path)
+            .arg("-o")
+            .arg(&binary_path)
+            .output()
+            .chain_err(|| format!("failed to run {}", rustc))?;
+        if !output.status.success() {
+            bail!("failed to compile the rustc guard: {}", String::from_utf8_lossy(&output.stderr));
+        }
+        store_cached_binary(&binary_path, &guard_path)?;
+        Ok(guard_path)
+    }
+
+    // Retrieve the exact dependencies for a given build from the artifacts
+    // cargo reports when building the tests running the examples again,
+    // which costs little as they are fresh, along with the directories of
+    // the build scripts that ran. Tests that are not fresh are not built
+    // again, but fail to build.
+    fn get_artifact_dependencies(
+        lock: &LockedDeps,
+        package_id: Option<&str>,
+        test_target: &str,
+        features: &[String],
+        root_dir: &Path,
+        target_dir: &Path,
+        target_triple: &str,
+    ) -> Result<(Vec<Dependency>, BuildDirs)> {
+        // The guard is set in cargo's configuration rather than in `RUSTC`,
+        // which build scripts may watch, but which takes precedence over
+        // the configuration
+        if env::var_os("RUSTC").is_some() {
+            bail!("cannot build the tests with the rustc guard with RUSTC set");
+        }
+        let guard = rustc_guard(target_dir)?;
+        let guard = guard.to_str().ok_or("filename not utf8")?;
+
+        let cargo = env::var("CARGO").unwrap_or_else(|_| String::from("cargo"));
+        let mut cmd = Command::new(cargo);
+        // Building only the tests running the examples, as the others may
+        // not have been built, and with the same features, as otherwise
+        // cargo builds the dependencies again. Unlike `--lib`, which builds
+        // the library as a unit test, this builds the library the examples
+        // link to.
+        cmd.arg("--config")
+            .arg(format!("build.rustc={}", toml_string(guard)))
+            .arg("test")
+            .arg("--no-run")
+            .arg("--frozen")
+            .arg("--message-format=json")
+            .arg("--manifest-path")
+            .arg(root_dir.join("Cargo.toml"))
+            .arg("--test")
+            .arg(test_target)
+            .arg("--no-default-features");
+        if !features.is_empty() {
+            cmd.arg("--features").arg(features.join(","));
+        }
+
+        // Build in the same place as the tests, `<target dir>/<profile>` or
+        // `<target dir>/<triple>/<profile>` when cross-compiling
+        let profile = target_dir.file_name().and_then(OsStr::to_str).ok_or("no profile")?;
+        let mut root_target_dir = target_dir.parent().ok_or("no target directory")?;
+        let cross_compiling = host_target_dir(target_dir, target_triple).is_some();
+        if cross_compiling {
+            cmd.arg("--target").arg(target_triple);
+            root_target_dir = root_target_dir.parent().ok_or("no target directory")?;
+        }
+        cmd.arg("--target-dir").arg(root_target_dir);
+        match profile {
+            "debug" => (),
+            "release" => {
+                cmd.arg("--release");
+            }
+            profile => {
+                cmd.arg("--profile").arg(profile);
+            }
+        }
+
+        let output = cmd.output()?;
+        if !output.status.success() {
+            bail!("failed to build the tests: {:?}", cmd);
+        }
+
+        // Libraries with their build profile, to tell the build of a
+        // dependency for the tests from that for build scripts
+        let mut candidates: Vec<(Dependency, Value)> = Vec::new();
+        let mut package_profile = None;
+        let mut build_dirs = Vec::new();
+        let manifest_path = root_dir.join("Cargo.toml").canonicalize()?;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let message: Value = match serde_json::from_str(line) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            if message["reason"] == "build-script-executed" {
+                // `out_dir` is next to the `output` file
+                if let Some(dir) = message["out_dir"].as_str().and_then(|d| Path::new(d).parent()) {
+                    let own = package_id.map_or(false, |id| message["package_id"] == id);
+                    build_dirs.push((dir.to_owned(), own));
+                }
+                continue;
+            }
+            if message["reason"] != "compiler-artifact" {
+                continue;
+            }
+            let locked = match message["package_id"].as_str().and_then(|id| lock.contains(id)) {
+                Some(locked) => locked,
+                None => continue,
+            };
+            let kinds = message["target"]["kind"].as_array();
+            let has_kind = |name: &str| kinds.map_or(false, |kinds| kinds.iter().any(|kind| kind == name));
+            let is_proc_macro = has_kind("proc-macro");
+            let is_lib = is_proc_macro || has_kind("lib") || has_kind("rlib") || has_kind("dylib");
+            // Libraries built as unit tests are not for linking
+            if !is_lib || message["profile"]["test"] == true {
+                continue;
+            }
+            // If cargo had to build something, it is not what the tests
+            // were built with, e.g. because of different features
+            if message["fresh"] == false {
+                bail!("dependencies were rebuilt");
+            }
+
+            let rlib = message["filenames"]
+                .as_array()
+                .into_iter()
+                .flat_map(|filenames| filenames.iter())
+                .filter_map(|filename| filename.as_str())
+                .map(PathBuf::from)
+                .find(|filename| {
+                    filename.extension().map_or(false, |ext| {
+                        ext == "rlib" || ext == "so" || ext == "dylib" || ext == "dll"
+                    })
+                })
+                // When cross-compiling, libraries are also built for the
+                // host as dependencies of build scripts and procedural
+                // macros, which only the macros themselves are
+                .filter(|rlib| !cross_compiling || is_proc_macro || rlib.starts_with(target_dir));
+            let is_package = message["manifest_path"]
+                .as_str()
+                .and_then(|path| Path::new(path).canonicalize().ok())
+                .map_or(false, |path| path == manifest_path);
+            if is_package {
+                package_profile = Some(message["profile"].clone());
+            }
+            if let Some(rlib) = rlib {
+                if candidates.iter().all(|(dep, _)| dep.rlib != rlib) {
+                    let dep = Dependency {
+                        libname: locked.extern_name.clone(),
+                        rlib,
+                    };
+                    candidates.push((dep, message["profile"].clone()));
+                }
+            }
+        }
+
+        // A library built more than once is both a dependency of the tests
+        // and of a build script, which is built with different settings
+        // than the package itself
+        let mut deps: Vec<Dependency> = Vec::new();
+        for (dep, _) in &candidates {
+            if deps.iter().any(|found| found.libname == dep.libname) {
+                continue;
+            }
+            let mut same_name = candidates.iter().filter(|(other, _)| other.libname == dep.libname);
+            let mut matching = candidates.iter().filter(|(other, profile)| {
+                other.libname == dep.libname && Some(profile) == package_profile.as_ref()
+            });
+            let found = match (same_name.nth(1), matching.next(), matching.next()) {
+                (None, _, _) => dep,
+                (Some(_), Some((found, _)), None) => found,
+                _ => bail!("cannot tell which build of `{}` the tests use", dep.libname),
+            };
+            deps.push(Dependency {
+                libname: found.libname.clone(),
+                rlib: found.rlib.clone(),
+            });
+        }
+        Ok((deps, build_dirs))
+    }
+
+    /// Compiles and, depending on its mode, runs an example, returning
+    /// what happened.
+    ///
+    /// An example that does not behave as expected is not an error: the
+    /// outcome says why it failed. Errors are problems running the
+    /// example at all, like failing to read the package's metadata.
+    pub fn run(spec: &TestSpec) -> Result<Outcome> {
+        let mut outdir = ExampleDir::new()?;
+        let mut outcome = Outcome::default();
+        let result = run_example(spec, &outdir, &mut outcome);
+        outcome.kept_dir = outdir.keep_files(result.is_err() || outcome.failure.is_some());
+        if let Some(ref dir) = outcome.kept_dir {
+            let kept = format!("the example's files were kept in {}", dir.display());
+            match (&result, outcome.failure.as_mut()) {
+                (&Ok(()), Some(failure)) => failure.push_str(&format!("\n{}", kept)),
+                (&Ok(()), None) => (),
+                // There is no outcome to report it in
+                (&Err(_), _) => eprintln!("{}", kept),
+            }
+        }
+        result.map(|()| outcome)
+    }
+
+    /// Runs an example like `run`, forwarding the compiler's output, and
+    /// panics if it fails. This is what the generated tests call.
+    pub fn test(spec: &TestSpec) {
+        let outcome = run(spec).unwrap_or_else(|e| {
+            let causes: Vec<String> = e.iter().map(|cause| cause.to_string()).collect();
+            panic!("{}: {}", spec.source_map.start(), causes.join(": "))
+        });
+        eprint!("{}", outcome.compile_output);
+        for diagnostic in &outcome.diagnostics {
+            eprint!("{}", diagnostic.rendered);
+        }
+        if let Some(ref failure) = outcome.failure {
+            panic!("{}", failure);
+        }
+        if let Some(ref dir) = outcome.kept_dir {
+            // Unlike writes to `io::stderr()`, this is captured by the test
+            // harness
+            eprintln!("the example's files were kept in {}", dir.display());
+        }
+    }
+
+    /// Compiles an example, panicking if it fails to compile.
+    #[deprecated(note = "use `rt::test` or `rt::run` with `Mode::Compile`")]
+    pub fn compile_test(root_dir: &str, out_dir: &str, target_triple: &str, test_text: &str) {
+        test_with_defaults(root_dir, out_dir, target_triple, test_text, Mode::Compile)
+    }
+
+    /// Compiles and runs an example, panicking if it fails to compile or
+    /// to run successfully.
+    #[deprecated(note = "use `rt::test` or `rt::run` with `Mode::Run`")]
+    pub fn run_test(root_dir: &str, out_dir: &str, target_triple: &str, test_text: &str) {
+        let mode = Mode::Run {
+            expected_output: None,
+            should_panic: None,
+        };
+        test_with_defaults(root_dir, out_dir, target_triple, test_text, mode)
+    }
+
+    // Tests an example the way tests generated by older versions did, with
+    // rustc, the package's default settings and no example-specific ones
+    fn test_with_defaults(root_dir: &str, out_dir: &str, target_triple: &str, test_text: &str, mode: Mode) {
+        let build = BuildConfig {
+            root_dir,
+            out_dir,
+            target_triple,
+            backend: Backend::Rustc,
+            features: &["default"],
+            profile: Profile {
+                opt_level: "0",
+                debug_assertions: true,
+            },
+            rustc_config: RustcConfig {
+                wrapper: None,
+                workspace_wrapper: None,
+                flags: &[],
+            },
+        };
+        test(&TestSpec {
+            build: &build,
+            source: test_text,
+            source_map: &SourceMap::new("test.rs", 1, 1, &[]),
+            edition: None,
+            mode,
+            merged: None,
+            env: &[],
+            forward_output: true,
+            output_limit: DEFAULT_OUTPUT_LIMIT,
+            timeout: None,
+        })
+    }
+
+    fn run_example(spec: &TestSpec, outdir: &ExampleDir, outcome: &mut Outcome) -> Result<()> {
+        let testcase_path = &outdir.path().join("test.rs");
+        let binary_path = &outdir.path().join("out.exe");
+
+        let (expected_output, should_panic) = match spec.mode {
+            Mode::Compile => {
+                let compilation =
+                    compile_example(spec, testcase_path, binary_path, CompileType::Check, outcome)?;
+                outcome.failure = compilation.check_success(spec.source_map);
+                return Ok(());
+            }
+            Mode::CompileFail(error_codes) => {
+                let compilation =
+                    compile_example(spec, testcase_path, binary_path, CompileType::Full, outcome)?;
+                outcome.failure = compilation.check_failure(spec.source_map, error_codes);
+                return Ok(());
+            }
+            Mode::Run {
+                expected_output,
+                should_panic,
+            } => (expected_output, should_panic),
+        };
+
+        let build = spec.build;
+        // If the merged binary can't be compiled, the example is compiled
+        // on its own
+        let merged_binary = spec
+            .merged
+            .and_then(|merged| Some((merged, merged_binary(build, merged.source, spec.edition)?)));
+        let mut command = match merged_binary {
+            Some((merged, merged_path)) => {
+                if outdir.keep != KeepTemp::Never {
+                    write_test_case(testcase_path, spec.source)?;
+                    let _ = fs::copy(&merged_path, binary_path);
+                    // The command compiles the whole merged binary
+                    let merged_dir = merged_dir(build, merged_key(merged.source));
+                    let _ = fs::copy(merged_dir.join("compile.sh"), outdir.path().join("compile.sh"));
+                }
+                let mut command = test_case_command(&merged_path, outdir.path(), build.target_triple);
+                command.env("SKEPTIC_EXAMPLE", merged.name);
+                command
+            }
+            None => {
+                let cached_path = cached_example_path(build, spec.source, spec.edition)?;
+                let mut run_path = binary_path;
+                match cached_path {
+                    // Examples whose files are kept are compiled again, for the command
+                    Some(ref cached_path) if cached_path.exists() && outdir.keep == KeepTemp::Never => {
+                        run_path = cached_path;
+                    }
+                    _ => {
+                        let compilation =
+                            compile_example(spec, testcase_path, binary_path, CompileType::Full, outcome)?;
+                        outcome.failure = compilation.check_success(spec.source_map);
+                        if outcome.failure.is_some() {
+                            return Ok(());
+                        }
+                        // Otherwise the example still runs from the temporary directory
+                        if let Some(ref cached_path) = cached_path {
+                            if store_cached_binary(binary_path, cached_path).is_ok() {
+                                run_path = cached_path;
+                            }
+                        }
+                    }
+                }
+                test_case_command(run_path, outdir.path(), build.target_triple)
+            }
+        };
+        for &(key, value) in spec.env {
+            command.env(key, value);
+        }
+
+        outcome.failure = check_run(command, spec, expected_output, should_panic, outcome)?;
+        Ok(())
+    }
+
+    // Writes the example to `in_path` and compiles it, recording the
+    // compiler's output in the outcome
+    fn compile_example(
+        spec: &TestSpec,
+        in_path: &Path,
+        out_path: &Path,
+        compile_type: CompileType,
+        outcome: &mut Outcome,
+    ) -> Result<Compilation> {
+        write_test_case(in_path, spec.source)?;
+        let start = Instant::now();
+        let compilation = compile_test_case(
+            in_path,
+            out_path,
+            spec.build,
+            compile_type,
+            spec.source_map,
+            spec.edition,
+        )?;
+        outcome.compile_time = start.elapsed();
+        outcome.compile_output = compilation.output.clone();
+        outcome.diagnostics = compilation.diagnostics.clone();
+        Ok(compilation)
+    }
+
+    fn rustc() -> String {
+        env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"))
+    }
+
+    // Merged binaries compiled by this process, by the hash of their source,
+    // or `None` for those that could not be compiled
+    static MERGED_BINARIES: Mutex<Vec<(u64, Option<PathBuf>)>> = Mutex::new(Vec::new());
+
+    fn merged_key(merged_source: &str) -> u64 {
+        let mut hasher = StableHasher::default();
+        merged_source.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // The directory a merged binary is compiled in, which holds its
+    // `merged.rs` and, when the files of examples may be kept, the command
+    // it was compiled with as `compile.sh`
+    fn merged_dir(build: &BuildConfig, key: u64) -> PathBuf {
+        Path::new(build.out_dir).join(format!("skeptic-merged-{:016x}", key))
+    }
+
+    fn merged_binary(build: &BuildConfig, merged_source: &str, edition: Option<&str>) -> Option<PathBuf> {
+        let key = merged_key(merged_source);
+
+        // Tests of the same binary wait for the first one to compile it
+        let mut binaries = MERGED_BINARIES.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, binary)) = binaries.iter().find(|&&(k, _)| k == key) {
+            return binary.clone();
+        }
+        let binary = compile_merged(build, merged_source, edition, key);
+        binaries.push((key, binary.clone()));
+        binary
+    }
+
+    fn compile_merged(
+        build: &BuildConfig,
+        merged_source: &str,
+        edition: Option<&str>,
+        key: u64,
+    ) -> Option<PathBuf> {
+        let package_edition = package_edition(build).ok()?;
+        if edition.unwrap_or(&package_edition) == "2015" {
+            return None;
+        }
+
+        let cached_path = cached_example_path(build, merged_source, edition).ok()?;
+        if let Some(ref cached_path) = cached_path {
+            // Compiled again when examples' files may be kept, for the command
+            if cached_path.exists() && keep_temp() == KeepTemp::Never {
+                return Some(cached_path.clone());
+            }
+        }
+
+        let dir = merged_dir(build, key);
+        fs::create_dir_all(&dir).ok()?;
+        let testcase_path = &dir.join("merged.rs");
+        let binary_path = dir.join("merged.exe");
+
+        write_test_case(testcase_path, merged_source).ok()?;
+        let compilation = compile_test_case(
+            testcase_path,
+            &binary_path,
+            build,
+            CompileType::Full,
+            &SourceMap::new("merged.rs", 1, 1, &[]),
+            edition,
+        ).ok()?;
+        if !compilation.success {
+            return None;
+        }
+        match cached_path {
+            Some(cached_path) if store_cached_binary(&binary_path, &cached_path).is_ok() => Some(cached_path),
+            _ => Some(binary_path),
+        }
+    }
+
+    // The edition of the package, which examples are compiled with unless
+    // they choose another one
+    fn package_edition(build: &BuildConfig) -> Result<String> {
+        match build.backend {
+            Backend::Rustc => {
+                let package = PackageInfo::get(build).chain_err(|| "failed to read dependencies")?;
+                Ok(package.edition.clone().ok_or("failed to read Cargo.toml")?)
+            }
+            Backend::Cargo => {
+                let deps = ScratchDependencies::get(build).chain_err(|| "failed to read Cargo.toml")?;
+                Ok(deps.edition.clone())
+            }
+        }
+    }
+
+    // Where the binary of an example is cached. Examples built with cargo
+    // are not, as cargo keeps the builds of their packages itself, and
+    // reading what goes into compiling them with rustc could fail.
+    fn cached_example_path(build: &BuildConfig, source: &str, edition: Option<&str>) -> Result<Option<PathBuf>> {
+        if build.backend == Backend::Cargo {
+            return Ok(None);
+        }
+        let args = rustc_args(build, edition)?;
+        cached_binary_path(&rustc(), build, source, &args).map(Some)
+    }
+
+    // Runs a compiled example, recording its output in the outcome, and
+    // checks that it behaves as expected
+    fn check_run(
+        mut command: Command,
+        spec: &TestSpec,
+        expected_output: Option<(usize, &str)>,
+        should_panic: Option<&str>,
+        outcome: &mut Outcome,
+    ) -> Result<Option<String>> {
+        // Enough of stdout is kept to compare it with the expected output
+        let stdout_limit = match expected_output {
+            Some((_, expected)) => std::cmp::max(spec.output_limit, expected.len() + 1),
+            None => spec.output_limit,
+        };
+        let start = Instant::now();
+        let (status, timed_out, stdout, stderr) = run_command(
+            &mut command,
+            spec.forward_output,
+            stdout_limit,
+            spec.output_limit,
+            spec.timeout,
+        )?;
+        outcome.run_time = Some(start.elapsed());
+        outcome.status = Some(status);
+        outcome.timed_out = timed_out;
+        outcome.stdout = String::from_utf8_lossy(&stdout.bytes).into_owned();
+        outcome.stdout_omitted = stdout.omitted;
+        outcome.stderr = String::from_utf8_lossy(&stderr.bytes).into_owned();
+        outcome.stderr_omitted = stderr.omitted;
+
+        let source_map = spec.source_map;
+        if let (true, Some(timeout)) = (timed_out, spec.timeout) {
+            return Ok(Some(format!(
+                "{}: example timed out after {} s{}{}",
+                source_map.start(),
+                timeout.as_secs_f64(),
+                output_section("stdout", &outcome.stdout, outcome.stdout_omitted),
+                output_section("stderr", &outcome.stderr, outcome.stderr_omitted)
+            )));
+        }
+        if let Some(message) = should_panic {
+            return Ok(check_panic(outcome, source_map, message));
+        }
+        if !status.success() {
+            return Ok(Some(format!(
+                "Command failed:\n{:?}{}{}",
+                command,
+                output_section("stdout", &outcome.stdout, outcome.stdout_omitted),
+                output_section("stderr", &outcome.stderr, outcome.stderr_omitted)
+            )));
+        }
+
+        if let Some((index, expected)) = expected_output {
+            let expected = normalize_output(expected);
+            let actual = normalize_output(&outcome.stdout);
+            let differs = expected != actual || outcome.stdout_omitted > 0;
+            if differs && bless_enabled() && outcome.stdout_omitted == 0 {
+                bless_output(spec.build.root_dir, source_map, index, &outcome.stdout)?;
+            } else if differs {
+                let mut message = format!(
+                    "{}: example output differs from the expected output \
+                     (- expected, + actual):\n{}",
+                    source_map.start(),
+                    diff_lines(&expected, &actual)
+                );
+                if outcome.stdout_omitted > 0 {
+                    message.push_str(&format!("[{} more bytes omitted]\n", outcome.stdout_omitted));
+                }
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    // The beginning of an output stream of an example
+    #[derive(Clone, Default)]
+    struct Captured {
+        bytes: Vec<u8>,
+        // The number of bytes beyond the limit
+        omitted: usize,
+    }
+
+    // Runs a command, keeping the beginning of its stdout and stderr and
+    // passing them on as they are printed if `forward` is set. If it runs
+    // longer than `timeout`, it is killed, and the second value returned
+    // is `true`.
+    fn run_command(
+        command: &mut Command,
+        forward: bool,
+        stdout_limit: usize,
+        stderr_limit: usize,
+        timeout: Option<Duration>,
+    ) -> Result<(ExitStatus, bool, Captured, Captured)> {
+        // Only examples that can be killed get a process group of their own,
+        // so that the others still get the signal when the tests are
+        // interrupted from the terminal
+        if timeout.is_some() {
+            new_process_group(command);
+        }
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let (done, finished) = mpsc::channel();
+        let stdout = child.stdout.take().unwrap();
+        let stdout = spawn_capture(stdout, if forward { Some(io::stdout()) } else { None }, stdout_limit, &done);
+        let stderr = child.stderr.take().unwrap();
+        let stderr = spawn_capture(stderr, if forward { Some(io::stderr()) } else { None }, stderr_limit, &done);
+        drop(done);
+
+        let mut timed_out = false;
+        let status = match timeout {
+            Some(timeout) => match wait_timeout(&mut child, timeout)? {
+                Some(status) => status,
+                None => {
+                    timed_out = true;
+                    kill_process_group(&mut child);
+                    child.wait()?
+                }
+            },
+            None => child.wait()?,
+        };
+        // The pipes are closed once the processes the example started are
+        // gone too. Those that outlive a killed example, like those that
+        // are not killed with it on Windows, are only waited for a moment.
+        let deadline = Instant::now() + Duration::from_secs(1);
+        for _ in 0..2 {
+            let result = if timed_out {
+                match finished.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(result) => result,
+                    Err(mpsc::RecvTimeoutError::Timeout) => break,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => bail!("failed to read the example's output"),
+                }
+            } else {
+                finished.recv().map_err(|_| "failed to read the example's output")?
+            };
+            result?;
+        }
+        let stdout = stdout.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let stderr = stderr.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        Ok((status, timed_out, stdout, stderr))
+    }
+
+    // Captures an output stream of an example on a thread of its own, which
+    // sends the result to `done` once the stream ends
+    fn spawn_capture<R, W>(reader: R, forward: Option<W>, limit: usize, done: &Sender<Result<()>>) -> Arc<Mutex<Captured>>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let captured = Arc::new(Mutex::new(Captured::default()));
+        let (thread_captured, done) = (captured.clone(), done.clone());
+        thread::spawn(move || {
+            let _ = done.send(capture(reader, forward, limit, &thread_captured));
+        });
+        captured
+    }
+
+    // Waits for a process to exit, returning `None` if it is still running
+    // after `timeout`
+    fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<Option<ExitStatus>> {
+        let deadline = Instant::now() + timeout;
+        let mut delay = Duration::from_millis(1);
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(status));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(std::cmp::min(delay, deadline - now));
+            delay = std::cmp::min(delay * 2, Duration::from_millis(50));
+        }
+    }
+
+    #[cfg(unix)]
+    fn new_process_group(command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        command.process_group(0);
+    }
+
+    #[cfg(not(unix))]
+    fn new_process_group(_command: &mut Command) {}
+
+    // Kills a process started with `new_process_group`, and the processes
+    // it started, which are in its group unless they left it
+    #[cfg(unix)]
+    fn kill_process_group(child: &mut Child) {
+        // The group has the id of the process that leads it
+        unsafe {
+            ::libc::kill(-(child.id() as ::libc::pid_t), ::libc::SIGKILL);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn kill_process_group(child: &mut Child) {
+        let _ = child.kill();
+    }
+
+    // Reads an output stream of an example to its end. Output that can't
+    // be forwarded is still kept.
+    fn capture<R: Read, W: Write>(
+        mut reader: R,
+        mut forward: Option<W>,
+        limit: usize,
+        captured: &Mutex<Captured>,
+    ) -> Result<()> {
+        let mut buf = [0; 8192];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(ref mut forward) = forward {
+                let _ = forward.write_all(&buf[..n]).and_then(|()| forward.flush());
+            }
+            let mut captured = captured.lock().unwrap_or_else(|e| e.into_inner());
+            let kept = std::cmp::min(n, limit - captured.bytes.len());
+            captured.bytes.extend_from_slice(&buf[..kept]);
+            captured.omitted += n - kept;
+        }
+        Ok(())
+    }
+
+    // An output stream of a failed example, for its failure message
+    fn output_section(name: &str, output: &str, omitted: usize) -> String {
+        if output.is_empty() && omitted == 0 {
+            return String::new();
+        }
+        let mut section = format!("\n--- {} ---\n{}", name, output);
+        if !output.ends_with('\n') {
+            section.push('\n');
+        }
+        if omitted > 0 {
+            section.push_str(&format!("[{} more bytes omitted]\n", omitted));
+        }
+        section
+    }
+
+    // Serializes rewrites of markdown files by tests running in parallel
+    static BLESS_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Whether expected outputs should be updated rather than checked,
+    /// as requested by setting `SKEPTIC_BLESS`.
+    fn bless_enabled() -> bool {
+        match env::var("SKEPTIC_BLESS") {
+            Ok(value) => !value.is_empty() && value != "0",
+            Err(_) => false,
+        }
+    }
+
+    // Rewrites the example's output block in its markdown file
+    fn bless_output(root_dir: &str, source_map: &SourceMap, index: usize, output: &str) -> Result<()> {
+        let _lock = BLESS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = Path::new(root_dir).join(&source_map.file);
+        let mut doc = String::new();
+        File::open(&path)
+            .and_then(|mut file| file.read_to_string(&mut doc))
+            .chain_err(|| format!("failed to read {}", path.display()))?;
+        let blessed = ::replace_output_block(&doc, index, output)
+            .ok_or("could not find the example's output block")?;
+        File::create(&path)
+            .and_then(|mut file| file.write_all(blessed.as_bytes()))
+            .chain_err(|| format!("failed to write {}", path.display()))?;
+        writeln!(io::stderr(), "{}: updated expected output", source_map.start())?;
+        Ok(())
+    }
+
+    // Ignore line ending style and trailing newlines, which are easily
+    // lost or added in markdown
+    fn normalize_output(output: &str) -> String {
+        output.replace("\r\n", "\n").trim_end_matches('\n').to_owned()
+    }
+
+    /// Renders a line-by-line diff of two texts, prefixing lines only
+    /// in `expected` with `-` and lines only in `actual` with `+`.
+    fn diff_lines(expected: &str, actual: &str) -> String {
+        let expected: Vec<&str> = expected.lines().collect();
+        let actual: Vec<&str> = actual.lines().collect();
+        let (n, m) = (expected.len(), actual.len());
+
+        // Longest common subsequence table, filled from the end so the
+        // diff can be read off from the start
+        let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i][j] = if expected[i] == actual[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    std::cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+                };
+            }
+        }
+
+        let mut out = String::new();
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && expected[i] == actual[j] {
+                out.push_str(&format!("  {}\n", expected[i]));
+                i += 1;
+                j += 1;
+            } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+                out.push_str(&format!("- {}\n", expected[i]));
+                i += 1;
+            } else {
+                out.push_str(&format!("+ {}\n", actual[j]));
+                j += 1;
+            }
+        }
+        out
+    }
+
+    /// Which temporary directories of examples are kept, as requested by
+    /// setting `SKEPTIC_KEEP_TEMP` to `all` or `on-failure`.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum KeepTemp {
+        Never,
+        OnFailure,
+        All,
+    }
+
+    fn keep_temp() -> KeepTemp {
+        match env::var("SKEPTIC_KEEP_TEMP").as_ref().map(|value| &value[..]) {
+            Ok("all") => KeepTemp::All,
+            Ok("on-failure") => KeepTemp::OnFailure,
+            _ => KeepTemp::Never,
+        }
+    }
+
+    // The temporary directory an example is compiled and run in, which
+    // holds its `test.rs`, the binary and, when it is kept, the command
+    // it was compiled with as `compile.sh`
+    struct ExampleDir {
+        dir: Option<TempDir>,
+        keep: KeepTemp,
+    }
+
+    impl ExampleDir {
+        fn new() -> Result<ExampleDir> {
+            Ok(ExampleDir {
+                dir: Some(TempDir::new("rust-skeptic")?),
+                keep: keep_temp(),
+            })
+        }
+
+        fn path(&self) -> &Path {
+            self.dir.as_ref().unwrap().path()
+        }
+
+        // Keeps the directory instead of removing it, if it is to be kept
+        // given whether the example failed, returning its path
+        fn keep_files(&mut self, failed: bool) -> Option<PathBuf> {
+            let keep = match self.keep {
+                KeepTemp::All => true,
+                KeepTemp::OnFailure => failed,
+                KeepTemp::Never => false,
+            };
+            if !keep {
+                return None;
+            }
+            self.dir.take().map(TempDir::into_path)
+        }
+    }
+
+    impl Drop for ExampleDir {
+        fn drop(&mut self) {
+            // Running the example panicked, so there is no outcome to
+            // report the directory in
+            if std::thread::panicking() {
+                if let Some(dir) = self.keep_files(true) {
+                    // Unlike writes to `io::stderr()`, this is captured by
+                    // the test harness, which shows it with the failure
+                    eprintln!("the example's files were kept in {}", dir.display());
+                }
+            }
+        }
+    }
+
+    // Writes the command an example is compiled with as a shell script
+    // next to it, if its directory may be kept
+    fn keep_command(in_path: &Path, command: &Command) {
+        if keep_temp() == KeepTemp::Never {
+            return;
+        }
+        let mut script = String::from("#!/bin/sh\n");
+        if let Some(dir) = command.get_current_dir().map(Path::to_owned).or_else(|| env::current_dir().ok()) {
+            script.push_str(&format!("cd {}\n", shell_quote(dir.as_os_str())));
+        }
+        script.push_str("exec env");
+        for (key, value) in command.get_envs() {
+            match value {
+                Some(value) => {
+                    let mut var = key.to_owned();
+                    var.push("=");
+                    var.push(value);
+                    script.push_str(&format!(" {}", shell_quote(&var)));
+                }
+                None => script.push_str(&format!(" -u {}", shell_quote(key))),
+            }
+        }
+        script.push_str(&format!(" {}", shell_quote(command.get_program())));
+        for arg in command.get_args() {
+            script.push_str(&format!(" {}", shell_quote(arg)));
+        }
+        script.push('\n');
+        let _ = File::create(in_path.with_file_name("compile.sh"))
+            .and_then(|mut file| file.write_all(script.as_bytes()));
+    }
+
+    fn shell_quote(s: &OsStr) -> String {
+        format!("'{}'", s.to_string_lossy().replace("'", "'\\''"))
+    }
+
+    fn write_test_case(path: &Path, test_text: &str) -> Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(test_text.as_bytes())?;
+        Ok(())
+    }
+
+    // The directory cargo builds the tests in, `target/<profile>` or
+    // `target/<triple>/<profile>`, with the libraries in its `deps`.
+    static TARGET_DIRS: Mutex<Vec<(String, PathBuf)>> = Mutex::new(Vec::new());
+
+    fn target_dir(out_dir: &str) -> PathBuf {
+        let mut target_dirs = TARGET_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, target_dir)) = target_dirs.iter().find(|(o, _)| o == out_dir) {
+            return target_dir.clone();
+        }
+        let target_dir = find_target_dir(Path::new(out_dir));
+        target_dirs.push((out_dir.to_owned(), target_dir.clone()));
+        target_dir
+    }
+
+    fn find_target_dir(out_dir: &Path) -> PathBuf {
+        // The tests run from the `deps` directory, wherever the target
+        // directory is and whatever the profile is called
+        let test_dir = env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.to_owned()))
+            .filter(|dir| dir.file_name() == Some(OsStr::new("deps")));
+        if let Some(deps_dir) = test_dir.as_ref().and_then(|dir| dir.parent()) {
+            return deps_dir.to_owned();
+        }
+
+        // The build script's `OUT_DIR` is `<profile dir>/build/<package>-<hash>/out`
+        let build_dir = out_dir.parent().and_then(|dir| dir.parent());
+        if out_dir.file_name() == Some(OsStr::new("out"))
+            && build_dir.and_then(|dir| dir.file_name()) == Some(OsStr::new("build"))
+        {
+            if let Some(profile_dir) = build_dir.and_then(|dir| dir.parent()) {
+                return profile_dir.to_owned();
+            }
+        }
+
+        // Failing that, the profile is guessed from how this crate is
+        // built, as it is built like the tests
+        let root_target_dir = env::var_os("CARGO_TARGET_DIR")
+            .map(PathBuf::from)
+            .or_else(|| {
+                let metadata = read_metadata(Path::new("."), false).ok()?;
+                metadata["target_directory"].as_str().map(PathBuf::from)
+            })
+            .unwrap_or_else(|| PathBuf::from("target"));
+        root_target_dir.join(if cfg!(debug_assertions) { "debug" } else { "release" })
+    }
+
+    // The directory host artifacts are built in when `target_dir` is that of
+    // a cross-compilation, `<target dir>/<triple>/<profile>`, for which it
+    // is `<target dir>/<profile>`. Without `--target`, cargo builds in
+    // `<target dir>/<profile>` whatever the target directory is called.
+    fn host_target_dir(target_dir: &Path, target_triple: &str) -> Option<PathBuf> {
+        let profile = target_dir.file_name()?;
+        let parent = target_dir.parent()?;
+        if parent.file_name() != Some(OsStr::new(target_triple)) {
+            return None;
+        }
+        Some(parent.parent()?.join(profile))
+    }
+
+    // The arguments to compile an example with, other than its source and
+    // output files
+    fn rustc_args(build: &BuildConfig, edition: Option<&str>) -> Result<Vec<String>> {
+        let target_dir = target_dir(build.out_dir);
+        let mut deps_dir = target_dir.clone();
+        deps_dir.push("deps");
+
+        let mut args = vec![
+            String::from("--verbose"),
+            String::from("--crate-type=bin"),
+            String::from("--error-format=json"),
+        ];
+
+        let package = PackageInfo::get(build).chain_err(|| "failed to read dependencies")?;
+
+        // This has to come before "-L".
+        let edition = match edition {
+            Some(edition) => edition,
+            None => package.edition.as_ref().ok_or("failed to read Cargo.toml")?,
+        };
+        if edition != "2015" {
+            args.push(format!("--edition={}", edition));
+        }
+
+        args.push(String::from("-L"));
+        args.push(target_dir.display().to_string());
+        args.push(String::from("-L"));
+        args.push(deps_dir.display().to_string());
+        // The dependencies of procedural macros built for the host
+        if let Some(host_dir) = host_target_dir(&target_dir, build.target_triple) {
+            args.push(String::from("-L"));
+            args.push(format!("dependency={}", host_dir.join("deps").display()));
+        }
+        args.push(String::from("--target"));
+        args.push(build.target_triple.to_owned());
+
+        let profile = build.profile;
+        args.push(String::from("-C"));
+        args.push(format!("opt-level={}", profile.opt_level));
+        args.push(String::from("-C"));
+        args.push(format!("debug-assertions={}", if profile.debug_assertions { "yes" } else { "no" }));
+
+        args.extend(package.build_output.rustc_args());
+        args.extend(build.rustc_config.flags.iter().map(|flag| flag.to_string()));
+        for feature in feature_names(&package.features, build.features) {
+            args.push(String::from("--cfg"));
+            args.push(format!("feature=\"{}\"", feature));
+        }
+
+        for dep in &package.dependencies {
+            args.push(String::from("--extern"));
+            args.push(format!(
+                "{}={}",
+                dep.libname,
+                dep.rlib.to_str().ok_or("filename not utf8")?,
+            ));
+        }
+        Ok(args)
+    }
+
+    // FNV-1a. Unlike those of `DefaultHasher`, its hashes don't change from
+    // one Rust release to the next, which matters for the hashes naming
+    // files in the target directory.
+    struct StableHasher(u64);
+
+    impl Default for StableHasher {
+        fn default() -> StableHasher {
+            StableHasher(0xcbf2_9ce4_8422_2325)
+        }
+    }
+
+    impl Hasher for StableHasher {
+        fn write(&mut self, bytes: &[u8]) {
+            for &byte in bytes {
+                self.0 ^= u64::from(byte);
+                self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+
+        fn finish(&self) -> u64 {
+            self.0
+        }
+    }
+
+    // Compiled examples are kept in the target directory, named after a hash
+    // of everything that goes into compiling them, so that examples that did
+    // not change since they were last run are not compiled again. Nothing is
+    // removed from there but by `cargo clean`.
+    fn cached_binary_path(rustc: &str, build: &BuildConfig, test_text: &str, args: &[String]) -> Result<PathBuf> {
+        let target_dir = target_dir(build.out_dir);
+        let package = PackageInfo::get(build)
+            .chain_err(|| "failed to read dependencies")?;
+
+        let mut hasher = StableHasher::default();
+        test_text.hash(&mut hasher);
+        format!("{:?}", build.backend).hash(&mut hasher);
+        rustc_version(rustc)?.hash(&mut hasher);
+        args.hash(&mut hasher);
+        package.build_output.env.hash(&mut hasher);
+        // The paths of the rlibs are part of the arguments, but they can be
+        // rebuilt in place
+        for dep in &package.dependencies {
+            if let Ok(metadata) = fs::metadata(&dep.rlib) {
+                metadata.len().hash(&mut hasher);
+                metadata.modified().ok().hash(&mut hasher);
+            }
+        }
+
+        Ok(target_dir
+            .join("skeptic-cache")
+            .join(format!("{:016x}.exe", hasher.finish())))
+    }
+
+    // The `rustc -vV` output of the compilers used by this process
+    static RUSTC_VERSIONS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+    fn rustc_version(rustc: &str) -> Result<String> {
+        let mut versions = RUSTC_VERSIONS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, version)) = versions.iter().find(|(r, _)| r == rustc) {
+            return Ok(version.clone());
+        }
+        let output = Command::new(rustc)
+            .arg("-vV")
+            .output()
+            .chain_err(|| format!("failed to run {}", rustc))?;
+        let version = String::from_utf8_lossy(&output.stdout).into_owned();
+        versions.push((rustc.to_owned(), version.clone()));
+        Ok(version)
+    }
+
+    // Moves a compiled example into the cache. The binary is copied next to
+    // its final name first, so that other processes never see it partially
+    // written.
+    fn store_cached_binary(binary_path: &Path, cached_path: &Path) -> io::Result<()> {
+        fs::create_dir_all(cached_path.parent().unwrap())?;
+        let partial_path = cached_path.with_extension(format!("{}.partial", process::id()));
+        fs::copy(binary_path, &partial_path)?;
+        fs::rename(&partial_path, cached_path)
+    }
+
+    fn compile_test_case(
+        in_path: &Path,
+        out_path: &Path,
+        build: &BuildConfig,
+        compile_type: CompileType,
+        source_map: &SourceMap,
+        edition: Option<&str>,
+    ) -> Result<Compilation> {
+        if build.backend == Backend::Cargo {
+            return cargo_compile_test_case(in_path, out_path, build, compile_type, source_map, edition);
+        }
+
+        // OK, here's where a bunch of magic happens using assumptions
+        // about cargo internals. We are going to use rustc to compile
+        // the examples, but to do that we've got to tell it where to
+        // look for the rlibs with the -L flag, and what their names
+        // are with the --extern flag. These come from the artifacts
+        // cargo reports, or failing that, from parsing fingerprints
+        // out of the lockfile and looking them up in the fingerprint
+        // file.
+
+        // Like cargo, wrappers are given the compiler to run, the one of
+        // the workspace members after the other
+        let rustc = rustc();
+        let mut programs: Vec<&str> = Vec::new();
+        programs.extend(build.rustc_config.wrapper);
+        programs.extend(build.rustc_config.workspace_wrapper);
+        programs.push(&rustc);
+        let mut cmd = Command::new(programs[0]);
+        cmd.args(&programs[1..]);
+        cmd.arg(in_path).args(&rustc_args(build, edition)?);
+
+        // For `env!`
+        let package = PackageInfo::get(build)
+            .chain_err(|| "failed to read dependencies")?;
+        for (key, value) in &package.build_output.env {
+            cmd.env(key, value);
+        }
+
+        match compile_type {
+            CompileType::Full => cmd.arg("-o").arg(out_path),
+            CompileType::Check => {
+                cmd.arg(format!(
+                    "--emit=dep-info={0}.d,metadata={0}.m",
+                    out_path.display()
+                ))
+            }
+        };
+
+        keep_command(in_path, &cmd);
+        interpret_compiler_output(cmd, source_map)
+    }
+
+    /// The result of compiling an example.
+    struct Compilation {
+        command: Command,
+        success: bool,
+        diagnostics: Vec<Diagnostic>,
+        // What the compiler printed other than its diagnostics
+        output: String,
+    }
+
+    impl Compilation {
+        fn errors(&self) -> Vec<&Diagnostic> {
+            self.diagnostics.iter().filter(|d| d.level == "error").collect()
+        }
+
+        fn error_location(&self, source_map: &SourceMap) -> DocLocation {
+            self.errors()
+                .iter()
+                .filter_map(|d| d.location.clone())
+                .next()
+                .unwrap_or_else(|| source_map.start())
+        }
+
+        // Why the example failed, if it was expected to compile
+        fn check_success(&self, source_map: &SourceMap) -> Option<String> {
+            if self.success {
+                return None;
+            }
+            Some(format!(
+                "{}: example failed to compile\nCommand failed:\n{:?}",
+                self.error_location(source_map),
+                self.command
+            ))
+        }
+
+        // Why the example failed, if it was expected not to compile
+        fn check_failure(&self, source_map: &SourceMap, error_codes: &[&str]) -> Option<String> {
+            if self.success {
+                return Some(format!(
+                    "{}: example compiled successfully, but was expected to fail\n\
+                     Command succeeded:\n{:?}",
+                    source_map.start(),
+                    self.command
+                ));
+            }
+
+            let found: Vec<&str> = self.errors()
+                .iter()
+                .filter_map(|d| d.code.as_deref())
+                .collect();
+            let missing: Vec<&str> = error_codes
+                .iter()
+                .cloned()
+                .filter(|code| !found.contains(code))
+                .collect();
+            if missing.is_empty() {
+                return None;
+            }
+            Some(format!(
+                "{}: example failed to compile, but not with the expected \
+                 error code(s) {} (found: {})",
+                self.error_location(source_map),
+                missing.join(", "),
+                if found.is_empty() { String::from("none") } else { found.join(", ") }
+            ))
+        }
+    }
+
+    // What a scratch package needs to depend on the package under test
+    #[derive(Debug)]
+    struct ScratchDependencies {
+        root_dir: PathBuf,
+        // The features enabled for the tests, as in `CARGO_FEATURE_*`
+        features: Vec<String>,
+        edition: String,
+        // The dependency tables of the manifest
+        manifest: String,
+        lockfile: PathBuf,
+        // The build script passing on the `--cfg` flags and environment
+        // variables set by the package's, which cargo does not pass to
+        // dependents, if there are any
+        build_script: Option<String>,
+    }
+
+    static SCRATCH_DEPENDENCIES: Mutex<Vec<Arc<ScratchDependencies>>> = Mutex::new(Vec::new());
+
+    impl ScratchDependencies {
+        fn get(build: &BuildConfig) -> Result<Arc<ScratchDependencies>> {
+            let (root_dir, features) = (Path::new(build.root_dir), build.features);
+            let mut cache = SCRATCH_DEPENDENCIES.lock().unwrap_or_else(|e| e.into_inner());
+            let cached = cache
+                .iter()
+                .find(|deps| deps.root_dir == root_dir && deps.features == features)
+                .cloned();
+            if let Some(deps) = cached {
+                return Ok(deps);
+            }
+            let deps = Arc::new(ScratchDependencies::read(root_dir, &target_dir(build.out_dir), features)?);
+            cache.push(deps.clone());
+            Ok(deps)
+        }
+
+        fn read(root_dir: &Path, target_dir: &Path, features: &[&str]) -> Result<ScratchDependencies> {
+            let metadata = read_metadata(root_dir, false)?;
+            let package = find_package(&metadata, root_dir)
+                .ok_or_else(|| format!("no package found for {}", root_dir.display()))?;
+
+            let name = package["name"].as_str().ok_or("no package name")?;
+            // The package is built with the features of the tests, which
+            // include `default` if it is enabled
+            let package_features: Vec<String> = feature_names(&declared_features(package), features)
+                .iter()
+                .map(|feature| toml_string(feature))
+                .collect();
+            let mut tables: Vec<(Option<String>, String)> = vec![(
+                None,
+                format!(
+                    "{} = {{ path = {}, default-features = false, features = [{}] }}\n",
+                    toml_key(name),
+                    toml_string(&root_dir.display().to_string()),
+                    package_features.join(", ")
+                ),
+            )];
+            let mut keys = vec![name.to_owned()];
+            for dep in package["dependencies"].as_array().into_iter().flat_map(|deps| deps.iter()) {
+                // Examples can use the normal and dev-dependencies of the
+                // package, but not those that are only enabled by features
+                if dep["kind"] == "build" || dep["optional"] == true {
+                    continue;
+                }
+                let key = dep["rename"].as_str().or_else(|| dep["name"].as_str()).ok_or("no dependency name")?;
+                if keys.iter().any(|k| k == key) {
+                    continue;
+                }
+                keys.push(key.to_owned());
+                let target = dep["target"].as_str().map(|t| t.to_owned());
+                tables.push((target, format!("{} = {}\n", toml_key(key), manifest_dependency(dep)?)));
+            }
+
+            let mut manifest = String::from("[dependencies]\n");
+            for (_, line) in tables.iter().filter(|(target, _)| target.is_none()) {
+                manifest.push_str(line);
+            }
+            for (target, line) in &tables {
+                if let Some(target) = target {
+                    manifest.push_str(&format!("\n[target.{}.dependencies]\n{}", toml_string(target), line));
+                }
+            }
+
+            // The package's build script ran when the tests were built
+            let build_dirs: BuildDirs = get_build_dirs(Some(name), target_dir)
+                .into_iter()
+                .filter(|&(_, own)| own)
+                .collect();
+            let build_output = BuildOutput::read(&build_dirs);
+
+            let workspace_root = metadata["workspace_root"].as_str().ok_or("no workspace root")?;
+            Ok(ScratchDependencies {
+                root_dir: root_dir.to_owned(),
+                features: features.iter().map(|f| f.to_string()).collect(),
+                edition: package["edition"].as_str().unwrap_or("2015").to_owned(),
+                manifest,
+                lockfile: Path::new(workspace_root).join("Cargo.lock"),
+                build_script: scratch_build_script(&build_output),
+            })
+        }
+    }
+
+    fn scratch_build_script(build_output: &BuildOutput) -> Option<String> {
+        if build_output.cfgs.is_empty() && build_output.env.is_empty() {
+            return None;
+        }
+        let mut lines = Vec::new();
+        for cfg in &build_output.cfgs {
+            lines.push(format!("cargo:rustc-cfg={}", cfg));
+            // Declared, so that newer versions of cargo don't warn about it
+            let check_cfg = match cfg.find('=') {
+                Some(i) => format!("cfg({}, values({}))", &cfg[..i], &cfg[i + 1..]),
+                None => format!("cfg({})", cfg),
+            };
+            lines.push(format!("cargo:rustc-check-cfg={}", check_cfg));
+        }
+        for (key, value) in &build_output.env {
+            lines.push(format!("cargo:rustc-env={}={}", key, value));
+        }
+        let mut script = String::from("fn main() {\n");
+        for line in lines {
+            script.push_str(&format!("    println!(\"{{}}\", {:?});\n", line));
+        }
+        script.push_str("}\n");
+        Some(script)
+    }
+
+    // The inline table declaring a dependency as `cargo metadata` describes it
+    fn manifest_dependency(dep: &Value) -> Result<String> {
+        let name = dep["name"].as_str().ok_or("no dependency name")?;
+        let mut fields = vec![format!("package = {}", toml_string(name))];
+        if let Some(path) = dep["path"].as_str() {
+            fields.push(format!("path = {}", toml_string(path)));
+        } else if let Some(source) = dep["source"].as_str().filter(|s| s.starts_with("git+")) {
+            // `git+<url>?branch=<branch>#<revision>`
+            let url = source["git+".len()..].split('#').next().unwrap();
+            let mut parts = url.splitn(2, '?');
+            fields.push(format!("git = {}", toml_string(parts.next().unwrap())));
+            for query in parts.next().into_iter().flat_map(|q| q.split('&')) {
+                let mut query = query.splitn(2, '=');
+                if let (Some(key), Some(value)) = (query.next(), query.next()) {
+                    fields.push(format!("{} = {}", key, toml_string(value)));
+                }
+            }
+        } else if let Some(registry) = dep["registry"].as_str() {
+            fields.push(format!("registry-index = {}", toml_string(registry)));
+        }
+        if let Some(req) = dep["req"].as_str() {
+            fields.push(format!("version = {}", toml_string(req)));
+        }
+        if dep["uses_default_features"] == false {
+            fields.push(String::from("default-features = false"));
         }
-
-        fn version(&self) -> Option<String> {
-            self.version.clone()
+        let features: Vec<String> = dep["features"]
+            .as_array()
+            .into_iter()
+            .flat_map(|features| features.iter())
+            .filter_map(|feature| feature.as_str())
+            .map(toml_string)
+            .collect();
+        if !features.is_empty() {
+            fields.push(format!("features = [{}]", features.join(", ")));
         }
+        Ok(format!("{{ {} }}", fields.join(", ")))
     }
 
-    fn get_edition<P: AsRef<Path>>(path: P) -> Result<String> {
-        let path = path.as_ref().join("Cargo.toml");
-        let manifest = cargo_metadata::metadata(Some(&path))?;
-        let edition = manifest.packages.iter()
-            .map(|package| &package.edition)
-            .max_by_key(|edition| u64::from_str(edition).unwrap())
-            .unwrap()
-            .clone();
-        Ok(edition)
+    // JSON strings are valid TOML basic strings
+    fn toml_string(s: &str) -> String {
+        Value::String(s.to_owned()).to_string()
     }
 
-    // Retrieve the exact dependencies for a given build by
-    // cross-referencing the lockfile with the fingerprint file
-    fn get_rlib_dependencies<P: AsRef<Path>>(
-        root_dir: P,
-        target_dir: P,
-    ) -> Result<Vec<Fingerprint>> {
-        let root_dir = root_dir.as_ref();
-        let target_dir = target_dir.as_ref();
-        let lock = LockedDeps::from_path(root_dir).or_else(
-            |_| {
-                // could not find Cargo.lock in $CARGO_MAINFEST_DIR
-                // try relative to target_dir
-                let mut root_dir = PathBuf::from(target_dir);
-                root_dir.pop();
-                root_dir.pop();
-                LockedDeps::from_path(root_dir)
-            },
-        )?;
+    fn toml_key(key: &str) -> String {
+        if key.chars().all(|c| c == '_' || c == '-' || c.is_ascii_alphanumeric()) {
+            key.to_owned()
+        } else {
+            toml_string(key)
+        }
+    }
 
-        let fingerprint_dir = target_dir.join(".fingerprint/");
-        let locked_deps: HashMap<String, String> = lock.collect();
-        let mut found_deps: HashMap<String, Fingerprint> = HashMap::new();
+    // Builds an example as a package of its own, in a directory of the
+    // target directory named after a hash of the example. Builds share a
+    // target directory, so dependencies are only built once.
+    fn cargo_compile_test_case(
+        in_path: &Path,
+        out_path: &Path,
+        build: &BuildConfig,
+        compile_type: CompileType,
+        source_map: &SourceMap,
+        edition: Option<&str>,
+    ) -> Result<Compilation> {
+        let deps = ScratchDependencies::get(build).chain_err(|| "failed to read Cargo.toml")?;
+        let mut source = String::new();
+        File::open(in_path)
+            .and_then(|mut file| file.read_to_string(&mut source))
+            .chain_err(|| "failed to read the example")?;
+        let edition = edition.unwrap_or(&deps.edition);
+
+        let mut hasher = StableHasher::default();
+        source.hash(&mut hasher);
+        edition.hash(&mut hasher);
+        deps.build_script.hash(&mut hasher);
+        let name = format!("skeptic-example-{:016x}", hasher.finish());
+        let scratch_dir = target_dir(build.out_dir).join("skeptic-scratch");
+        let package_dir = scratch_dir.join(&name);
+
+        // An empty `[workspace]` keeps the package out of any workspace the
+        // target directory is in
+        let manifest = format!(
+            "[package]\nname = \"{0}\"\nversion = \"0.0.0\"\nedition = \"{1}\"\nbuild = {2}\n\n\
+             [[bin]]\nname = \"{0}\"\npath = \"test.rs\"\n\n[workspace]\n\n{3}",
+            name,
+            edition,
+            if deps.build_script.is_some() { "\"build.rs\"" } else { "false" },
+            deps.manifest
+        );
+        fs::create_dir_all(&package_dir).chain_err(|| "failed to create the example's package")?;
+        File::create(package_dir.join("Cargo.toml"))
+            .and_then(|mut file| file.write_all(manifest.as_bytes()))
+            .chain_err(|| "failed to write the example's Cargo.toml")?;
+        if let Some(ref build_script) = deps.build_script {
+            write_test_case(&package_dir.join("build.rs"), build_script)?;
+        }
+        write_test_case(&package_dir.join("test.rs"), &source)?;
+        if deps.lockfile.exists() {
+            fs::copy(&deps.lockfile, package_dir.join("Cargo.lock"))
+                .chain_err(|| "failed to copy Cargo.lock")?;
+        }
 
-        for finger in WalkDir::new(fingerprint_dir)
-            .into_iter()
-            .filter_map(|v| v.ok())
-            .filter_map(|v| Fingerprint::from_path(v.path()).ok())
-        {
-            if let Some(locked_ver) = locked_deps.get(&finger.name()) {
-                // TODO this should be refactored to something more readable
-                match (found_deps.entry(finger.name()), finger.version()) {
-                    (Entry::Occupied(mut e), Some(ver)) => {
-                        // we find better match only if it is exact version match
-                        // and has fresher build time
-                        if *locked_ver == ver && e.get().mtime < finger.mtime {
-                            e.insert(finger);
-                        }
-                    }
-                    (Entry::Vacant(e), ver) => {
-                        // we see an exact match or unversioned version
-                        if ver.unwrap_or_else(|| locked_ver.clone()) == *locked_ver {
-                            e.insert(finger);
-                        }
-                    }
-                    _ => (),
+        let cargo = env::var("CARGO").unwrap_or_else(|_| String::from("cargo"));
+        let mut cmd = Command::new(cargo);
+        match compile_type {
+            CompileType::Full => cmd.arg("build"),
+            CompileType::Check => cmd.arg("check"),
+        };
+        // The example is built with the `dev` profile, set up like the one
+        // of the tests
+        let (profile, rustc_config) = (build.profile, build.rustc_config);
+        cmd.env("CARGO_PROFILE_DEV_OPT_LEVEL", profile.opt_level)
+            .env("CARGO_PROFILE_DEV_DEBUG_ASSERTIONS", profile.debug_assertions.to_string())
+            .env("CARGO_ENCODED_RUSTFLAGS", rustc_config.flags.join("\x1f"))
+            .env_remove("RUSTFLAGS");
+        match rustc_config.wrapper {
+            Some(wrapper) => cmd.env("RUSTC_WRAPPER", wrapper),
+            None => cmd.env_remove("RUSTC_WRAPPER"),
+        };
+        match rustc_config.workspace_wrapper {
+            Some(wrapper) => cmd.env("RUSTC_WORKSPACE_WRAPPER", wrapper),
+            None => cmd.env_remove("RUSTC_WORKSPACE_WRAPPER"),
+        };
+        cmd.arg("--offline")
+            .arg("--message-format=json")
+            .arg("--manifest-path")
+            .arg(package_dir.join("Cargo.toml"))
+            .arg("--target-dir")
+            .arg(scratch_dir.join("target"))
+            .arg("--target")
+            .arg(build.target_triple);
+
+        keep_command(in_path, &cmd);
+        let output = cmd.output()?;
+
+        let mut diagnostics = Vec::new();
+        let mut executable = None;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let message: Value = match serde_json::from_str(line) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            // Diagnostics of dependencies are not the example's
+            if message["target"]["name"] != name.as_str() {
+                continue;
+            }
+            if message["reason"] == "compiler-message" {
+                if let Some(diagnostic) = Diagnostic::from_json(&message["message"].to_string(), source_map) {
+                    diagnostics.push(diagnostic);
                 }
+            } else if message["reason"] == "compiler-artifact" {
+                executable = message["executable"].as_str().map(PathBuf::from);
             }
         }
 
-        Ok(
-            found_deps
-                .into_iter()
-                .filter_map(|(_, val)| if val.rlib.exists() { Some(val) } else { None })
-                .collect(),
-        )
+        let mut success = output.status.success();
+        if let (CompileType::Full, true) = (compile_type, success) {
+            success = executable.map_or(false, |executable| fs::copy(executable, out_path).is_ok());
+        }
+        Ok(Compilation {
+            command: cmd,
+            success,
+            diagnostics,
+            output: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
     }
 
-    pub fn compile_test(root_dir: &str, out_dir: &str, target_triple: &str, test_text: &str) {
-        let rustc = &env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
-        let outdir = &TempDir::new("rust-skeptic").unwrap();
-        let testcase_path = &outdir.path().join("test.rs");
-        let binary_path = &outdir.path().join("out.exe");
+    fn interpret_compiler_output(mut command: Command, source_map: &SourceMap) -> Result<Compilation> {
+        let output = command.output()?;
+        let mut compiler_output = String::from_utf8_lossy(&output.stdout).into_owned();
+
+        let mut diagnostics = Vec::new();
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            match Diagnostic::from_json(line, source_map) {
+                Some(diagnostic) => diagnostics.push(diagnostic),
+                None => {
+                    compiler_output.push_str(line);
+                    compiler_output.push('\n');
+                }
+            }
+        }
 
-        write_test_case(testcase_path, test_text);
-        compile_test_case(
-            testcase_path,
-            binary_path,
-            rustc,
-            root_dir,
-            out_dir,
-            target_triple,
-            CompileType::Check,
-        );
+        Ok(Compilation {
+            command,
+            success: output.status.success(),
+            diagnostics,
+            output: compiler_output,
+        })
     }
 
-    pub fn run_test(root_dir: &str, out_dir: &str, target_triple: &str, test_text: &str) {
-        let rustc = &env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
-        let outdir = &TempDir::new("rust-skeptic").unwrap();
-        let testcase_path = &outdir.path().join("test.rs");
-        let binary_path = &outdir.path().join("out.exe");
+    fn test_case_command(program_path: &Path, outdir: &Path, target_triple: &str) -> Command {
+        let mut runner = runner(target_triple).into_iter();
+        let mut cmd = match runner.next() {
+            Some(program) => {
+                let mut cmd = Command::new(program);
+                cmd.args(runner).arg(program_path);
+                cmd
+            }
+            None => Command::new(program_path),
+        };
+        cmd.current_dir(outdir);
+        cmd
+    }
 
-        write_test_case(testcase_path, test_text);
-        compile_test_case(
-            testcase_path,
-            binary_path,
-            rustc,
-            root_dir,
-            out_dir,
-            target_triple,
-            CompileType::Full,
+    // The program examples are run through, with its arguments, like an
+    // emulator or `valgrind`. `SKEPTIC_RUNNER` sets it only for examples,
+    // and takes precedence over the runner set for the target in the
+    // environment, `CARGO_TARGET_<triple>_RUNNER`. Runners set in cargo's
+    // configuration files are not read. Like with cargo, the program and
+    // arguments are separated by whitespace.
+    fn runner(target_triple: &str) -> Vec<String> {
+        let cargo_runner = format!(
+            "CARGO_TARGET_{}_RUNNER",
+            target_triple.to_uppercase().replace("-", "_").replace(".", "_")
         );
-        run_test_case(binary_path, outdir.path());
+        env::var("SKEPTIC_RUNNER")
+            .ok()
+            .filter(|runner| !runner.trim().is_empty())
+            .or_else(|| env::var(cargo_runner).ok())
+            .map_or_else(Vec::new, |runner| runner.split_whitespace().map(String::from).collect())
     }
 
-    fn write_test_case(path: &Path, test_text: &str) {
-        let mut file = File::create(path).unwrap();
-        file.write_all(test_text.as_bytes()).unwrap();
-    }
 
-    fn compile_test_case(
-        in_path: &Path,
-        out_path: &Path,
-        rustc: &str,
-        root_dir: &str,
-        out_dir: &str,
-        target_triple: &str,
-        compile_type: CompileType,
-    ) {
+    // The exit code of a process whose main thread panicked
+    const PANIC_EXIT_CODE: i32 = 101;
 
-        // OK, here's where a bunch of magic happens using assumptions
-        // about cargo internals. We are going to use rustc to compile
-        // the examples, but to do that we've got to tell it where to
-        // look for the rlibs with the -L flag, and what their names
-        // are with the --extern flag. This is going to involve
-        // parsing fingerprints out of the lockfile and looking them
-        // up in the fingerprint file.
-
-        let root_dir = PathBuf::from(root_dir);
-        let mut target_dir = PathBuf::from(out_dir);
-        target_dir.pop();
-        target_dir.pop();
-        target_dir.pop();
-        let mut deps_dir = target_dir.clone();
-        deps_dir.push("deps");
+    // Why the example failed, if it was expected to panic
+    fn check_panic(outcome: &Outcome, source_map: &SourceMap, message: &str) -> Option<String> {
+        let status = outcome.status?;
+        if status.success() {
+            return Some(format!("{}: example should have panicked, but ran successfully", source_map.start()));
+        }
+        if status.code() != Some(PANIC_EXIT_CODE) {
+            return Some(format!(
+                "{}: example should have panicked, but it failed with {}{}",
+                source_map.start(),
+                status,
+                output_section("stderr", &outcome.stderr, outcome.stderr_omitted)
+            ));
+        }
+        if !outcome.stderr.contains(message) {
+            return Some(format!(
+                "{}: example panicked, but not with the expected message {:?}{}",
+                source_map.start(),
+                message,
+                output_section("stderr", &outcome.stderr, outcome.stderr_omitted)
+            ));
+        }
+        None
+    }
 
-        let mut cmd = Command::new(rustc);
-        cmd.arg(in_path)
-            .arg("--verbose")
-            .arg("--crate-type=bin");
+    #[cfg(test)]
+    mod tests {
+        use super::*;
 
-        // This has to come before "-L".
-        let edition = get_edition(&root_dir).expect("failed to read Cargo.toml");
-        if edition != "2015" {
-            cmd.arg(format!("--edition={}", edition));
+        fn host_triple() -> String {
+            let version = rustc_version(&rustc()).unwrap();
+            let host = version.lines().find_map(|line| line.strip_prefix("host: "));
+            host.expect("no host in rustc -vV").to_owned()
         }
-        
-        cmd.arg("-L")
-            .arg(&target_dir)
-            .arg("-L")
-            .arg(&deps_dir)
-            .arg("--target")
-            .arg(&target_triple);
 
-
-        for dep in get_rlib_dependencies(root_dir, target_dir).expect(
-            "failed to read dependencies",
-        )
-        {
-            cmd.arg("--extern");
-            cmd.arg(format!(
-                "{}={}",
-                dep.libname,
-                dep.rlib.to_str().expect("filename not utf8"),
-            ));
+        #[test]
+        fn dependencies_are_found_from_the_tests_artifacts() {
+            // Then only cargo's files are read
+            if env::var_os("RUSTC").is_some() {
+                return;
+            }
+            let package = TempDir::new("skeptic-package").unwrap();
+            let root_dir = package.path();
+            fs::create_dir(root_dir.join("src")).unwrap();
+            fs::create_dir(root_dir.join("tests")).unwrap();
+            let files = [
+                (
+                    "Cargo.toml",
+                    "[package]\nname = \"fixture\"\nversion = \"0.1.0\"\n\n\
+                     [features]\ndefault = [\"std\"]\nstd = []\nextra = []\n",
+                ),
+                ("src/lib.rs", "pub fn f() {}\n"),
+                ("tests/examples.rs", "#[test]\nfn t() {}\n"),
+                ("tests/others.rs", "#[test]\nfn t() {}\n"),
+            ];
+            for &(path, contents) in &files {
+                File::create(root_dir.join(path)).unwrap().write_all(contents.as_bytes()).unwrap();
+            }
+            let target_dir = root_dir.join("target");
+            let cargo = env::var("CARGO").unwrap_or_else(|_| String::from("cargo"));
+            let status = Command::new(cargo)
+                .args(["test", "--no-run", "--offline", "--quiet", "--features", "extra", "--test", "examples"])
+                .arg("--target-dir")
+                .arg(&target_dir)
+                .current_dir(root_dir)
+                .status()
+                .unwrap();
+            assert!(status.success());
+
+            let metadata = read_metadata(root_dir, true).unwrap();
+            let lock = LockedDeps::from_metadata(&metadata, root_dir).unwrap();
+            let package_id = find_package(&metadata, root_dir).and_then(|p| p["id"].as_str());
+            let features = vec![String::from("extra"), String::from("std"), String::from("default")];
+            let (deps, _) = get_artifact_dependencies(
+                &lock,
+                package_id,
+                "examples",
+                &features,
+                root_dir,
+                &target_dir.join("debug"),
+                &host_triple(),
+            ).unwrap();
+
+            assert_eq!(deps.len(), 1);
+            assert_eq!(deps[0].libname, "fixture");
+            assert!(deps[0].rlib.exists());
+
+            // Tests that would be built with other settings are not built
+            let outputs = || fs::read_dir(target_dir.join("debug").join("deps")).unwrap().count();
+            let built = outputs();
+            let features = vec![String::from("std"), String::from("default")];
+            assert!(get_artifact_dependencies(
+                &lock,
+                package_id,
+                "examples",
+                &features,
+                root_dir,
+                &target_dir.join("debug"),
+                &host_triple(),
+            ).is_err());
+            assert_eq!(outputs(), built);
         }
 
-        match compile_type {
-            CompileType::Full => cmd.arg("-o").arg(out_path),
-            CompileType::Check => {
-                cmd.arg(format!(
-                    "--emit=dep-info={0}.d,metadata={0}.m",
-                    out_path.display()
-                ))
+        #[test]
+        fn builds_of_the_same_version_are_ambiguous() {
+            let target = TempDir::new("skeptic-target").unwrap();
+            let target_dir = target.path().join("debug");
+            let lock = LockedDeps {
+                dependencies: vec![LockedDep {
+                    extern_name: String::from("dep"),
+                    lib_name: String::from("dep"),
+                    version: String::from("1.0.0"),
+                    package_id: String::from("dep 1.0.0"),
+                }],
+            };
+            let build = |hash: &str| {
+                let fingerprint_dir = target_dir.join(".fingerprint").join(format!("dep-{}", hash));
+                fs::create_dir_all(&fingerprint_dir).unwrap();
+                fs::create_dir_all(target_dir.join("deps")).unwrap();
+                File::create(fingerprint_dir.join("lib-dep.json"))
+                    .unwrap()
+                    .write_all(b"{\"local\": {\"Precalculated\": \"1.0.0\"}}")
+                    .unwrap();
+                File::create(target_dir.join("deps").join(format!("libdep-{}.rlib", hash))).unwrap();
+            };
+
+            build("0123456789abcdef");
+            let deps = get_rlib_dependencies(&lock, &target_dir, &host_triple()).unwrap();
+            assert_eq!(deps.len(), 1);
+            assert!(deps[0].rlib.ends_with("libdep-0123456789abcdef.rlib"));
+
+            build("fedcba9876543210");
+            match *get_rlib_dependencies(&lock, &target_dir, &host_triple()).unwrap_err().kind() {
+                ErrorKind::AmbiguousBuild(ref lib_name, ref rlibs) => {
+                    assert_eq!(lib_name, "dep");
+                    assert_eq!(rlibs.len(), 2);
+                }
+                ref kind => panic!("unexpected error: {}", kind),
             }
-        };
+        }
 
-        interpret_output(cmd);
-    }
+        #[test]
+        fn cross_builds_are_told_from_the_target_triple() {
+            let triple = "aarch64-unknown-linux-gnu";
+            assert_eq!(
+                host_target_dir(Path::new("/p/target/aarch64-unknown-linux-gnu/debug"), triple),
+                Some(PathBuf::from("/p/target/debug"))
+            );
+            assert_eq!(host_target_dir(Path::new("/p/target/debug"), triple), None);
+            // Whatever the target directory is called
+            assert_eq!(host_target_dir(Path::new("/p/build-output/release"), triple), None);
+        }
 
-    fn run_test_case(program_path: &Path, outdir: &Path) {
-        let mut cmd = Command::new(program_path);
-        cmd.current_dir(outdir);
-        interpret_output(cmd);
-    }
-
-    fn interpret_output(mut command: Command) {
-        let output = command.output().unwrap();
-        write!(
-            io::stdout(),
-            "{}",
-            String::from_utf8(output.stdout).unwrap()
-        ).unwrap();
-        write!(
-            io::stderr(),
-            "{}",
-            String::from_utf8(output.stderr).unwrap()
-        ).unwrap();
-        if !output.status.success() {
-            panic!("Command failed:\n{:?}", command);
+        #[test]
+        fn cache_keys_are_stable() {
+            let hash = |bytes: &[u8]| {
+                let mut hasher = StableHasher::default();
+                hasher.write(bytes);
+                hasher.finish()
+            };
+            assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+            assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+            assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+        }
+
+        #[test]
+        fn only_the_files_of_failing_examples_are_kept_on_failure() {
+            let mut passed = ExampleDir::new().unwrap();
+            passed.keep = KeepTemp::OnFailure;
+            let passed_path = passed.path().to_owned();
+            assert_eq!(passed.keep_files(false), None);
+            drop(passed);
+            assert!(!passed_path.exists());
+
+            let mut failed = ExampleDir::new().unwrap();
+            failed.keep = KeepTemp::OnFailure;
+            let failed_path = failed.path().to_owned();
+            assert_eq!(failed.keep_files(true), Some(failed_path.clone()));
+            drop(failed);
+            assert!(failed_path.exists());
+            fs::remove_dir_all(failed_path).unwrap();
+        }
+
+        #[test]
+        fn rendered_diagnostics_point_into_the_document() {
+            let rendered = concat!(
+                "error[E0308]: mismatched types\n",
+                "  --> /tmp/skeptic/test.rs:9:18\n",
+                "   |\n",
+                " 9 |     let x: i32 = \"a\";\n",
+                "   |            ---   ^^^ expected `i32`, found `&str`\n",
+                "10 |     x;\n",
+                "   |\n",
+                "   = note: a note\n",
+            );
+            let map = SourceMap::new("README.md", 9, 99, &[-4, -4]);
+
+            assert_eq!(
+                remap_rendered(rendered, &map),
+                concat!(
+                    "error[E0308]: mismatched types\n",
+                    "   --> README.md:99:14\n",
+                    "    |\n",
+                    " 99 |     let x: i32 = \"a\";\n",
+                    "    |            ---   ^^^ expected `i32`, found `&str`\n",
+                    "100 |     x;\n",
+                    "    |\n",
+                    "    = note: a note\n",
+                )
+            );
         }
     }
 }
@@ -897,9 +4272,18 @@ mod tests {
     #[test]
     fn test_markdown_files_of_directory() {
         let files = vec![
+            "../../tests/build-output-test.md",
+            "../../tests/cargo-backend-test.md",
+            "../../tests/compile-fail-test.md",
+            "../../tests/edition-test.md",
+            "../../tests/features-test.md",
             "../../tests/hashtag-test.md",
+            "../../tests/merged-test.md",
+            "../../tests/output-test.md",
+            "../../tests/renamed-dependency-test.md",
             "../../tests/section-names.md",
             "../../tests/should-panic-test.md",
+            "../../tests/timeout-test.md",
         ];
         let files: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
         assert_eq!(markdown_files_of_directory("../../tests/"), files);
@@ -946,7 +4330,7 @@ mod tests {
 
 
         let tests =
-            extract_tests_from_string(&create_test_input(&get_lines(lines)), &String::from("blah"));
+            extract_tests_from_string(&test_config(), &create_test_input(&get_lines(lines)), Path::new("blah.md")).unwrap();
 
         let test_names: Vec<String> = tests
             .0
@@ -989,7 +4373,7 @@ mod tests {
             ```"###);
 
         let tests =
-            extract_tests_from_string(&create_test_input(&get_lines(lines)), &String::from("blah"));
+            extract_tests_from_string(&test_config(), &create_test_input(&get_lines(lines)), Path::new("blah.md")).unwrap();
 
         let test_names: Vec<String> = tests
             .0
@@ -1026,7 +4410,7 @@ mod tests {
             "###,
         );
         let tests =
-            extract_tests_from_string(&create_test_input(&get_lines(lines)), &String::from("blah"));
+            extract_tests_from_string(&test_config(), &create_test_input(&get_lines(lines)), Path::new("blah.md")).unwrap();
         assert_eq!(tests.1, Some(expected));
     }
 
@@ -1043,11 +4427,342 @@ mod tests {
             "###,
         );
         let tests =
-            extract_tests_from_string(&create_test_input(&get_lines(lines)), &String::from("blah"));
+            extract_tests_from_string(&test_config(), &create_test_input(&get_lines(lines)), Path::new("blah.md")).unwrap();
         assert_eq!(tests.1, None);
     }
 
 
+    #[test]
+    fn section_names_respect_heading_depth_and_naming() {
+        let lines = unindent(
+            r###"
+            ## Section
+
+            ### Subsection
+
+            ```rust
+            fn main() {}
+            ```
+            "###,
+        );
+        let text = create_test_input(&get_lines(lines));
+
+        let mut config = test_config();
+        let tests = extract_tests_from_string(&config, &text, Path::new("blah.md")).unwrap();
+        assert_eq!(tests.0[0].name, "blah_sect_section_line_5");
+
+        config.heading_depth = 3;
+        let tests = extract_tests_from_string(&config, &text, Path::new("blah.md")).unwrap();
+        assert_eq!(tests.0[0].name, "blah_sect_subsection_line_5");
+
+        config.naming = TestNaming::Line;
+        let tests = extract_tests_from_string(&config, &text, Path::new("blah.md")).unwrap();
+        assert_eq!(tests.0[0].name, "blah_line_5");
+    }
+
+    #[test]
+    fn malformed_info_string_reports_file_and_line() {
+        let lines = unindent(
+            r###"
+            Some prose.
+
+            ```rust,skt-
+            fn main() {}
+            ```
+            "###,
+        );
+        let text = create_test_input(&get_lines(lines));
+
+        let err = extract_tests_from_string(&test_config(), &text, Path::new("blah.md"))
+            .err()
+            .expect("malformed info string was accepted");
+        match *err.kind() {
+            ErrorKind::MalformedInfoString(ref info, ref file, line, _) => {
+                assert_eq!(info, "rust,skt-");
+                assert_eq!(file, Path::new("blah.md"));
+                assert_eq!(line, 3);
+            }
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn source_map_accounts_for_template_and_hidden_lines() {
+        let template = "fn main() {{\n    {}\n}}";
+        let lines = get_lines(String::from("let x = 1;\n  # let y = 2;\nx + y;"));
+        let (first_line, shifts) = create_source_map(template, &lines);
+
+        assert_eq!(first_line, 3);
+        assert_eq!(shifts, vec![-4, 4, 0]);
+
+        let map = rt::SourceMap::new("README.md", first_line, 10, &shifts);
+        assert_eq!(map.locate(2, 1), None);
+        assert_eq!(map.locate(3, 9).unwrap().to_string(), "README.md:10:5");
+        assert_eq!(map.locate(4, 5).unwrap().to_string(), "README.md:11:9");
+        assert_eq!(map.locate(5, 1).unwrap().to_string(), "README.md:12:1");
+    }
+
+    #[test]
+    fn only_rust_info_strings_are_validated() {
+        for info in &["text,E0308", "text,timeout=10h", "text,features=", r#"text,should_panic="open"#] {
+            let info = parse_code_block_info(info).unwrap();
+            assert!(!info.is_rust);
+        }
+
+        assert!(parse_code_block_info("rust,timeout=10h").is_err());
+        assert!(parse_code_block_info(r#"rust,should_panic="open"#).is_err());
+    }
+
+    #[test]
+    fn compile_fail_info_string_collects_error_codes() {
+        let info = parse_code_block_info("rust,compile_fail,E0308,E0599").unwrap();
+        assert!(info.is_rust);
+        assert!(info.compile_fail);
+        assert_eq!(info.error_codes, vec!["E0308", "E0599"]);
+
+        let info = parse_code_block_info("compile_fail").unwrap();
+        assert!(info.compile_fail);
+        assert!(info.error_codes.is_empty());
+
+        assert!(parse_code_block_info("rust,E0308").is_err());
+        assert!(parse_code_block_info("rust,compile_fail,should_panic").is_err());
+    }
+
+    #[test]
+    fn should_panic_info_string_takes_a_message() {
+        let info = parse_code_block_info(r#"rust,should_panic="index out of bounds, or worse",ignore"#)
+            .unwrap();
+        assert!(info.is_rust);
+        assert!(info.should_panic);
+        assert_eq!(info.panic_message, Some(String::from("index out of bounds, or worse")));
+        assert!(info.ignore);
+
+        let info = parse_code_block_info("rust,should_panic=overflow").unwrap();
+        assert_eq!(info.panic_message, Some(String::from("overflow")));
+
+        let info = parse_code_block_info("rust,should_panic").unwrap();
+        assert!(info.should_panic);
+        assert_eq!(info.panic_message, None);
+
+        assert!(parse_code_block_info(r#"rust,should_panic="unterminated"#).is_err());
+        assert!(parse_code_block_info(r#"rust,should_panic="""#).is_err());
+        assert!(parse_code_block_info(r#"rust,no_run,should_panic="boom""#).is_err());
+    }
+
+    #[test]
+    fn features_info_string_lists_features() {
+        let info = parse_code_block_info("rust,features=serde,async").unwrap();
+        assert!(info.is_rust);
+        assert_eq!(info.features, vec!["serde", "async"]);
+
+        let info = parse_code_block_info(r#"rust,features="serde, async",no_run"#).unwrap();
+        assert_eq!(info.features, vec!["serde", "async"]);
+        assert!(info.no_run);
+
+        let info = parse_code_block_info("rust,features=std,should_panic").unwrap();
+        assert_eq!(info.features, vec!["std"]);
+        assert!(info.should_panic);
+
+        assert!(parse_code_block_info("rust,features=").is_err());
+    }
+
+    #[test]
+    fn timeout_info_string_takes_a_duration() {
+        let info = parse_code_block_info("rust,timeout=10s").unwrap();
+        assert!(info.is_rust);
+        assert_eq!(info.timeout, Some(Duration::from_secs(10)));
+
+        let info = parse_code_block_info("rust,timeout=500ms,should_panic").unwrap();
+        assert_eq!(info.timeout, Some(Duration::from_millis(500)));
+        assert!(info.should_panic);
+
+        let info = parse_code_block_info("rust,timeout=2m").unwrap();
+        assert_eq!(info.timeout, Some(Duration::from_secs(120)));
+
+        let info = parse_code_block_info("rust,timeout=3").unwrap();
+        assert_eq!(info.timeout, Some(Duration::from_secs(3)));
+
+        assert!(parse_code_block_info("rust,timeout=").is_err());
+        assert!(parse_code_block_info("rust,timeout=0s").is_err());
+        assert!(parse_code_block_info("rust,timeout=10h").is_err());
+        assert!(parse_code_block_info("rust,no_run,timeout=10s").is_err());
+    }
+
+    #[test]
+    fn edition_info_string_overrides_edition() {
+        let info = parse_code_block_info("rust,edition2018").unwrap();
+        assert!(info.is_rust);
+        assert_eq!(info.edition, Some(String::from("2018")));
+
+        let info = parse_code_block_info("rust").unwrap();
+        assert_eq!(info.edition, None);
+
+        assert!(parse_code_block_info("rust,edition2019").is_err());
+    }
+
+    #[test]
+    fn output_block_is_attached_to_preceding_test() {
+        let lines = unindent(
+            r###"
+            ```rust
+            fn main() { println!("hi"); }
+            ```
+
+            Prints:
+
+            ```text,skt-output
+            hi
+            ```
+
+            ```rust
+            fn main() {}
+            ```
+            "###,
+        );
+        let text = create_test_input(&get_lines(lines));
+
+        let tests = extract_tests_from_string(&test_config(), &text, Path::new("blah.md"))
+            .unwrap()
+            .0;
+        assert_eq!(tests[0].expected_output, Some((0, String::from("hi\n"))));
+        assert_eq!(tests[1].expected_output, None);
+    }
+
+    #[test]
+    fn output_block_without_preceding_test_is_rejected() {
+        let lines = unindent(
+            r###"
+            ```rust
+            fn main() { println!("hi"); }
+            ```
+
+            ```toml
+            [dependencies]
+            ```
+
+            ```text,skt-output
+            hi
+            ```
+            "###,
+        );
+        let text = create_test_input(&get_lines(lines));
+
+        assert!(extract_tests_from_string(&test_config(), &text, Path::new("blah.md")).is_err());
+    }
+
+    #[test]
+    fn output_block_is_replaced_in_place() {
+        let doc = unindent(
+            r###"
+            ```rust
+            fn main() { println!("one"); }
+            ```
+
+            ```text,skt-output
+            first
+            ```
+
+            * A list item
+
+              ```text,skt-output
+              old
+              lines
+              ```
+
+            The end.
+            "###,
+        );
+
+        let expected = unindent(
+            r###"
+            ```rust
+            fn main() { println!("one"); }
+            ```
+
+            ```text,skt-output
+            first
+            ```
+
+            * A list item
+
+              ```text,skt-output
+              new
+              ```
+
+            The end.
+            "###,
+        );
+
+        assert_eq!(replace_output_block(&doc, 1, "new\n"), Some(expected));
+        assert_eq!(replace_output_block(&doc, 0, "first"), Some(doc.clone()));
+        assert_eq!(replace_output_block(&doc, 2, "none"), None);
+    }
+
+    #[test]
+    fn templates_expand_like_format() {
+        let template = "fn main() {{\n    {}\n}}";
+        assert_eq!(expand_template(template, "f();"), format!("fn main() {{\n    {}\n}}", "f();"));
+        assert_eq!(expand_template("{}", "{{}}"), "{{}}");
+    }
+
+    #[test]
+    fn only_compatible_examples_are_merged() {
+        let lines = unindent(
+            r###"
+            ```rust
+            fn main() {}
+            ```
+
+            ```rust,no_run
+            fn main() {}
+            ```
+
+            ```rust,edition2015
+            fn main() {}
+            ```
+
+            ```rust
+            extern crate skeptic;
+            fn main() {}
+            ```
+
+            ```rust
+            #![allow(dead_code)]
+            fn main() {}
+            ```
+            "###,
+        );
+        let (tests, _) = extract_tests_from_string(&test_config(), &lines, Path::new("blah.md")).unwrap();
+        let merged: Vec<bool> = tests
+            .iter()
+            .map(|test| can_be_merged(test, &create_test_input(&test.text)))
+            .collect();
+        assert_eq!(merged, vec![true, false, false, false, false]);
+    }
+
+    fn test_config() -> Config {
+        Config {
+            out_dir: PathBuf::from("out"),
+            root_dir: PathBuf::from("."),
+            out_file: PathBuf::from("out/skeptic-tests.rs"),
+            target_triple: String::from("x86_64-unknown-linux-gnu"),
+            docs: Vec::new(),
+            default_template: None,
+            heading_depth: 2,
+            naming: TestNaming::SectionAndLine,
+            merge_examples: false,
+            backend: Backend::Rustc,
+            features: Vec::new(),
+            opt_level: String::from("0"),
+            debug_assertions: true,
+            rustc_wrapper: None,
+            rustc_workspace_wrapper: None,
+            rustflags: Vec::new(),
+            output_limit: rt::DEFAULT_OUTPUT_LIMIT,
+            timeout: None,
+        }
+    }
+
     fn get_line_number_from_test_name(test: Test) -> String {
         String::from(test.name.split('_').last().expect(
             "There were no underscores!",
@@ -1 +1,69 @@
 include!(concat!(env!("OUT_DIR"), "/skeptic-tests.rs"));
+
+// Tests generated by older versions of skeptic call these
+#[test]
+#[allow(deprecated)]
+fn deprecated_entry_points_still_test_examples() {
+    let (root_dir, out_dir) = (env!("CARGO_MANIFEST_DIR"), env!("OUT_DIR"));
+    skeptic::rt::compile_test(root_dir, out_dir, SKEPTIC_BUILD.target_triple, "fn main() {}");
+    skeptic::rt::run_test(root_dir, out_dir, SKEPTIC_BUILD.target_triple, "fn main() {}");
+}
+
+fn run_with_timeout(source: &str, timeout: std::time::Duration) -> skeptic::rt::Outcome {
+    skeptic::rt::run(&skeptic::rt::TestSpec {
+        build: &SKEPTIC_BUILD,
+        source,
+        source_map: &skeptic::rt::SourceMap::new("timeout.md", 1, 1, &[]),
+        edition: None,
+        mode: skeptic::rt::Mode::Run {
+            expected_output: None,
+            should_panic: None,
+        },
+        merged: None,
+        env: &[],
+        forward_output: false,
+        output_limit: skeptic::rt::DEFAULT_OUTPUT_LIMIT,
+        timeout: Some(timeout),
+    }).unwrap()
+}
+
+#[test]
+fn examples_running_longer_than_their_timeout_are_killed() {
+    let outcome = run_with_timeout(
+        "fn main() { println!(\"started\"); loop {} }",
+        std::time::Duration::from_millis(500),
+    );
+    assert!(outcome.timed_out);
+    assert_eq!(outcome.stdout, "started\n");
+    assert!(outcome.failure.unwrap().contains("example timed out after 0.5 s"));
+}
+
+// A process the example started that is not killed with it, here because
+// it left the example's process group, keeps its output open
+#[cfg(unix)]
+#[test]
+fn processes_outliving_a_killed_example_are_not_waited_for() {
+    let outcome = run_with_timeout(
+        r#"
+use std::os::unix::process::CommandExt;
+use std::{env, process, thread, time};
+
+fn main() {
+    if env::args().count() > 1 {
+        thread::sleep(time::Duration::from_secs(5));
+        return;
+    }
+    process::Command::new(env::current_exe().unwrap())
+        .arg("child")
+        .process_group(0)
+        .spawn()
+        .unwrap();
+    loop {}
+}
+"#,
+        std::time::Duration::from_millis(500),
+    );
+    assert!(outcome.timed_out);
+    // The child, which outlives the example, keeps its stdout open
+    assert!(outcome.run_time.unwrap() < std::time::Duration::from_secs(4));
+}